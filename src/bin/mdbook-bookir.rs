@@ -6,7 +6,14 @@ use serde_json::Error;
 use std::{fs, io};
 use uuid::Uuid;
 
-fn main() -> io::Result<()> {
+fn main() {
+    if let Err(e) = render() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render() -> io::Result<()> {
     let mut stdin = io::stdin();
     let ctx = RenderContext::from_json(&mut stdin)
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -18,7 +25,7 @@ fn main() -> io::Result<()> {
     gen_collected_output::<BasicConfig>(
         &ctx,
         "bookir",
-        |path, src, book, config, output| {
+        |path, _, book, _, _| {
             use std::io::Write;
             let path = {
                 let mut dest = dest.clone();
@@ -1,17 +1,29 @@
 use mdbook::renderer::RenderContext;
+#[cfg(feature = "epub-signatures")]
+use mdbook_fiction_tools::epub::signature;
 use mdbook_fiction_tools::{
     bookir::RichTextOptions,
     epub::{
+        check,
         config::{EpubConfig, PackageId},
+        container,
         info::EpubFileInfo,
-        write_epub,
+        write_epub, OutputContext,
     },
-    gen_collected_output, helpers, Output,
+    gen_collected_output,
 };
 use std::{fs, io};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
-fn main() -> io::Result<()> {
+fn main() {
+    if let Err(e) = render() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render() -> io::Result<()> {
     let mut stdin = io::stdin();
     let ctx = RenderContext::from_json(&mut stdin)
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -30,18 +42,25 @@ fn main() -> io::Result<()> {
                 dest.set_extension("epub");
                 dest
             };
-            let file = fs::File::create(path)?;
+            config.check_features()?;
+            let exploded_dir = config
+                .save_temps
+                .then(|| container::exploded_dir(&dest, book.id))
+                .transpose()?;
+            let file = fs::File::create(&path)?;
 
-            let id = match output {
-                Output::Full => config.file_ids.full.as_ref().cloned(),
-                Output::Part(id) => config.file_ids.individual_files.get(id).cloned(),
-                _ => None,
-            };
+            let id = config.file_ids.get(output).cloned();
+            let reproducible_time = config.reproducible_time()?;
 
             let info = EpubFileInfo {
                 title: book.title.to_string(),
-                ident: id.unwrap_or_else(|| PackageId::Uuid {
-                    uuid: Uuid::now_v7(),
+                ident: id.unwrap_or_else(|| match reproducible_time {
+                    Some(_) => {
+                        PackageId::derived(ctx.config.book.title.as_deref().unwrap_or(""), book.id)
+                    }
+                    None => PackageId::Uuid {
+                        uuid: Uuid::now_v7(),
+                    },
                 }),
                 lang: ctx
                     .config
@@ -50,11 +69,35 @@ fn main() -> io::Result<()> {
                     .clone()
                     .unwrap_or_else(|| "en-us".to_string()),
                 creators: book.authors.iter().map(|v| v.to_string()).collect(),
+                modified: reproducible_time.unwrap_or_else(OffsetDateTime::now_utc),
+                metadata: config.metadata_for(output),
+                series: book
+                    .series
+                    .map(|series| (series.title.to_string(), series.position)),
             };
 
-            let pkg_id = book.id;
+            let cx = OutputContext {
+                output,
+                exploded_dir: exploded_dir.as_deref(),
+                root: &ctx.root,
+            };
+            write_epub(file, book, info, config, &cx)?;
+
+            check::check_output(&path, config.check)?;
+
+            // Catches a public key that does not belong to the private key, before the epub is published
+            #[cfg(feature = "epub-signatures")]
+            if let Some(pair) = &config.signing_key {
+                let key = signature::VerifyingKey::load(&pair.public, &ctx.root)?;
+                signature::verify_epub(fs::File::open(&path)?, &key).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("cannot verify the signature of `{}`: {e}", path.display()),
+                    )
+                })?;
+            }
 
-            write_epub(file, book, info, pkg_id.to_string())
+            Ok(())
         },
         RichTextOptions {
             ..Default::default()
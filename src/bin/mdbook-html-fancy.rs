@@ -0,0 +1,45 @@
+use mdbook::renderer::RenderContext;
+use mdbook_fiction_tools::{
+    bookir::RichTextOptions,
+    gen_collected_output,
+    html::{config::HtmlConfig, write_html},
+};
+use std::{fs, io};
+
+fn main() {
+    if let Err(e) = render() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render() -> io::Result<()> {
+    let mut stdin = io::stdin();
+    let ctx = RenderContext::from_json(&mut stdin)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let dest = ctx.destination.clone();
+
+    fs::create_dir_all(&dest)?;
+
+    gen_collected_output::<HtmlConfig>(
+        &ctx,
+        "html-fancy",
+        |path, _, book, config, _| {
+            let path = {
+                let mut dest = dest.clone();
+                dest.push(path);
+                dest.set_extension("html");
+                dest
+            };
+            let file = io::BufWriter::new(fs::File::create(path)?);
+
+            let lang = ctx.config.book.language.as_deref().unwrap_or("en");
+
+            write_html(file, book, lang, config)
+        },
+        RichTextOptions {
+            ..Default::default()
+        },
+    )
+}
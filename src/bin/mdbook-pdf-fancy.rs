@@ -1,14 +1,25 @@
 use mdbook::renderer::RenderContext;
 use mdbook_fiction_tools::{
     bookir::RichTextOptions,
-    gen_collected_output, helpers,
-    pdf::{config::PdfConfig, write_pdf},
+    gen_collected_output,
+    pdf::{
+        config::PdfConfig,
+        fonts::{OPEN_SANS, SOURCE_CODE_PRO},
+        write_pdf,
+    },
     Output,
 };
 use std::{fs, io};
 use uuid::Uuid;
 
-fn main() -> io::Result<()> {
+fn main() {
+    if let Err(e) = render() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render() -> io::Result<()> {
     let mut stdin = io::stdin();
     let ctx = RenderContext::from_json(&mut stdin)
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -19,24 +30,27 @@ fn main() -> io::Result<()> {
 
     gen_collected_output::<PdfConfig>(
         &ctx,
-        "epub-fancy",
-        |path, _, book, config, output| {
+        "pdf-fancy",
+        |path, src, book, config, output| {
             let path = {
                 let mut dest = dest.clone();
                 dest.push(path);
                 dest.set_extension("pdf");
                 dest
             };
-            let file = fs::File::create(path)?;
+            let file = io::BufWriter::new(fs::File::create(path)?);
 
             let file_id = match output {
                 Output::Full => config.file_ids.full.as_ref().cloned(),
-                Output::Part(id) => config.file_ids.individual_files.get(id).cloned(),
+                Output::Part(id) | Output::Chapter(id) => {
+                    config.file_ids.individual_files.get(id).cloned()
+                }
                 _ => None,
             };
 
-            let file_id = file_id.unwrap_or_else(|| Uuid::now_v7());
-            todo!()
+            let file_id = file_id.unwrap_or_else(Uuid::now_v7);
+
+            write_pdf(file, book, src, file_id, OPEN_SANS, SOURCE_CODE_PRO, config)
         },
         RichTextOptions {
             ..Default::default()
@@ -2,6 +2,8 @@ use std::io::{self, Stdin};
 use std::process::Stdio;
 use std::{
     borrow::{Borrow, Cow},
+    collections::HashMap,
+    iter::Peekable,
     num::NonZero,
     ops::ControlFlow,
     path::{Path, PathBuf},
@@ -12,7 +14,8 @@ use ::xml::{name::Name, reader::XmlEvent, EventReader};
 use mdbook::{book::BookItems, BookItem};
 use nav::NavTree;
 use pulldown_cmark::{
-    CodeBlockKind, Event, HeadingLevel as MdHeadingLevel, InlineStr, LinkType, Parser, Tag, TagEnd,
+    Alignment as MdAlignment, CodeBlockKind, Event, HeadingLevel as MdHeadingLevel, InlineStr,
+    LinkType, OffsetIter, Parser, Tag, TagEnd,
 };
 use serde::{Deserialize, Serialize};
 
@@ -21,6 +24,7 @@ use crate::helpers;
 #[cfg(feature = "math")]
 pub mod math;
 
+pub mod error;
 pub mod nav;
 pub mod render;
 pub mod str;
@@ -28,6 +32,7 @@ pub mod xml;
 
 use xml::XmlElem;
 
+pub use error::{RichTextError, RichTextErrorKind, RichTextErrors};
 pub use str::CowStr;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -78,6 +83,16 @@ pub struct CodeBlock<'a> {
     pub content: CowStr<'a>,
 }
 
+impl CodeBlock<'_> {
+    /// The language of the code block. The info string of a fenced code block starts with the language, which may be followed by other attributes (```` ```rust,ignore ````)
+    pub fn language(&self) -> &str {
+        self.lang
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Table<'a> {
     pub align: Vec<Alignment>,
@@ -87,7 +102,17 @@ pub struct Table<'a> {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TableRow<'a> {
-    pub elems: Vec<RichText<'a>>,
+    pub elems: Vec<TableCell<'a>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableCell<'a>(pub Vec<RichText<'a>>);
+
+/// The content of a footnote, referred to by [`Link::Footnote`] with the same label
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FootnoteDefinition<'a> {
+    pub label: CowStr<'a>,
+    pub content: Vec<RichText<'a>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,12 +133,67 @@ pub enum RichText<'a> {
     TextBreak(BreakType),
     List(List<'a>),
     Table(Table<'a>),
+    FootnoteDefinition(FootnoteDefinition<'a>),
     #[cfg(feature = "math")]
     MathBlock(math::Math<'a>),
     #[cfg(feature = "math")]
     InlineMath(math::Math<'a>),
 }
 
+/// Renders `elems` as plain text, dropping all styling and markup
+pub fn plain_text(elems: &[RichText]) -> String {
+    let mut text = String::new();
+    for elem in elems {
+        elem.walk(&mut |elem| match elem {
+            RichText::RawText(t)
+            | RichText::InlineCode(t)
+            | RichText::Xhtml(InlineXhtml::CData(t)) => text.push_str(t),
+            RichText::TextBreak(BreakType::SoftLine | BreakType::HardLine) => text.push(' '),
+            _ => {}
+        });
+    }
+    text
+}
+
+impl<'a> RichText<'a> {
+    /// Calls `f` with this element and every element nested inside of it, in document order
+    pub fn walk<'r, F: FnMut(&'r RichText<'a>)>(&'r self, f: &mut F) {
+        f(self);
+        let walk_all = |elems: &'r [RichText<'a>], f: &mut F| {
+            for elem in elems {
+                elem.walk(f);
+            }
+        };
+        match self {
+            RichText::Xhtml(InlineXhtml::Node(XmlNode::Block(_, elems)))
+            | RichText::Stylised(_, elems)
+            | RichText::Paragraph(elems)
+            | RichText::BlockQuote(elems)
+            | RichText::InternalLink(Link::Text { elems, .. })
+            | RichText::ExternalLink(Link::Text { elems, .. })
+            | RichText::InternalImage(Link::Text { elems, .. })
+            | RichText::ExternalImage(Link::Text { elems, .. })
+            | RichText::Heading(Heading { elems, .. })
+            | RichText::FootnoteDefinition(FootnoteDefinition { content: elems, .. }) => {
+                walk_all(elems, f)
+            }
+            RichText::List(list) => {
+                for item in &list.elems {
+                    walk_all(&item.0, f);
+                }
+            }
+            RichText::Table(table) => {
+                for row in table.head.iter().chain(&table.body) {
+                    for cell in &row.elems {
+                        walk_all(&cell.0, f);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ListStyle {
     Unordered,
@@ -140,7 +220,9 @@ pub enum BreakType {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Heading<'a> {
     pub level: HeadingLevel,
+    /// The content of the heading, as plain text. Used to derive the id of the heading and wherever styling cannot be shown, such as navigation labels
     pub text: CowStr<'a>,
+    pub elems: Vec<RichText<'a>>,
     pub id: CowStr<'a>,
 }
 
@@ -169,45 +251,151 @@ pub enum EndMarker<'a> {
 }
 
 #[derive(Debug)]
-pub struct RichTextParser<'a>(Parser<'a>);
+pub struct RichTextParser<'a> {
+    events: Peekable<OffsetIter<'a>>,
+    source: &'a str,
+    path: Option<PathBuf>,
+    /// The offset of the most recently read event
+    offset: usize,
+    /// An end marker that was read while recovering from an error, which closes an enclosing element
+    pending_end: Option<EndMarker<'a>>,
+    errors: Vec<RichTextError>,
+}
 
 impl<'a> RichTextParser<'a> {
     pub fn new(text: &'a str, options: RichTextOptions) -> Self {
         let mut md_options = pulldown_cmark::Options::ENABLE_TABLES
             | pulldown_cmark::Options::ENABLE_STRIKETHROUGH
             | pulldown_cmark::Options::ENABLE_GFM
+            | pulldown_cmark::Options::ENABLE_FOOTNOTES
             | pulldown_cmark::Options::ENABLE_HEADING_ATTRIBUTES;
         if options.math {
             md_options |= pulldown_cmark::Options::ENABLE_MATH;
         }
 
-        Self(Parser::new_ext(text, md_options))
+        Self {
+            events: Parser::new_ext(text, md_options)
+                .into_offset_iter()
+                .peekable(),
+            source: text,
+            path: None,
+            offset: 0,
+            pending_end: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Sets the path reported in errors
+    pub fn with_source_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Returns every problem found while parsing.
+    ///
+    /// Parsing continues past problems, so the elements produced by the parser are still usable (if possibly incomplete) when this returns an error.
+    pub fn finish(self) -> Result<(), RichTextErrors> {
+        RichTextErrors(self.errors).into_result(())
     }
 }
 
 impl<'a> RichTextParser<'a> {
+    fn next_event(&mut self) -> Option<Event<'a>> {
+        let (event, range) = self.events.next()?;
+        self.offset = range.start;
+        Some(event)
+    }
+
+    fn error(&mut self, kind: RichTextErrorKind) {
+        self.error_at(self.offset, kind);
+    }
+
+    fn error_at(&mut self, offset: usize, kind: RichTextErrorKind) {
+        self.errors.push(RichTextError::new(
+            self.path.clone(),
+            self.source,
+            offset,
+            kind,
+        ));
+    }
+
+    /// Reads a comment or CDATA section starting with `html`, which may span multiple html events (such as a multi-line comment in an html block),
+    /// and returns its content without the `open` and `close` delimiters
+    fn read_section(
+        &mut self,
+        html: &str,
+        open: &str,
+        close: &str,
+        what: &'static str,
+    ) -> CowStr<'a> {
+        let start = self.offset;
+        let mut html = html.to_string();
+        while !html.trim_end().ends_with(close) {
+            match self.events.peek() {
+                Some((Event::Html(_) | Event::InlineHtml(_) | Event::Text(_), _)) => {
+                    if let Some(Event::Html(more) | Event::InlineHtml(more) | Event::Text(more)) =
+                        self.next_event()
+                    {
+                        html.push_str(&more);
+                    }
+                }
+                Some((Event::SoftBreak, _)) => {
+                    self.next_event();
+                    html.push('\n');
+                }
+                _ => {
+                    self.error(RichTextErrorKind::Unterminated(what));
+                    let body = html.strip_prefix(open).unwrap_or(&html);
+                    return CowStr::Boxed(body.into());
+                }
+            }
+        }
+
+        let html = html.trim_end();
+        // The delimiters of `<!-->` overlap, so only one of them can be stripped
+        match html
+            .strip_prefix(open)
+            .and_then(|body| body.strip_suffix(close))
+        {
+            Some("") => {
+                self.error_at(
+                    start,
+                    RichTextErrorKind::InvalidXhtml(format!("`{html}` is an empty {what}")),
+                );
+                CowStr::Borrowed("")
+            }
+            Some(body) => CowStr::Boxed(body.into()),
+            None => {
+                self.error_at(
+                    start,
+                    RichTextErrorKind::InvalidXhtml(format!(
+                        "`{html}` is not a well-formed {what}"
+                    )),
+                );
+                CowStr::Borrowed("")
+            }
+        }
+    }
+
     fn next_primitive(&mut self) -> Option<Result<RichText<'a>, Event<'a>>> {
-        Some(match self.0.next()? {
+        Some(match self.next_event()? {
             e @ (Event::Start(_) | Event::End(_)) => Err(e),
             Event::Text(text) => Ok(RichText::RawText(text.into())),
             Event::Code(code) => Ok(RichText::InlineCode(code.into())),
             #[cfg(feature = "math")]
-            Event::InlineMath(tex) => todo!("latex {tex}"),
-            #[cfg(feature = "math")]
-            Event::DisplayMath(tex) => todo!("latex {tex}"),
+            Event::InlineMath(tex) | Event::DisplayMath(tex) => {
+                self.error(RichTextErrorKind::Unsupported("math"));
+                Ok(RichText::RawText(tex.into()))
+            }
             #[cfg(not(feature = "math"))]
             Event::InlineMath(_) | Event::DisplayMath(_) => unreachable!("No math support"),
             Event::InlineHtml(html) | Event::Html(html) => {
-                if let Some(comment) = html.strip_prefix("<!--") {
-                    let comment_body = comment.strip_suffix("-->")?;
-                    Ok(RichText::Xhtml(InlineXhtml::Comment(
-                        CowStr::Borrowed(comment_body).into_static(),
-                    )))
-                } else if let Some(cdata) = html.strip_prefix("<![CDATA[") {
-                    let cdata_body = cdata.strip_suffix("]]>")?;
-                    Ok(RichText::Xhtml(InlineXhtml::CData(
-                        CowStr::Borrowed(cdata_body).into_static(),
-                    )))
+                if html.starts_with("<!--") {
+                    let comment = self.read_section(&html, "<!--", "-->", "comment");
+                    Ok(RichText::Xhtml(InlineXhtml::Comment(comment)))
+                } else if html.starts_with("<![CDATA[") {
+                    let cdata = self.read_section(&html, "<![CDATA[", "]]>", "CDATA section");
+                    Ok(RichText::Xhtml(InlineXhtml::CData(cdata)))
                 } else {
                     Err(Event::InlineHtml(html))
                 }
@@ -216,11 +404,21 @@ impl<'a> RichTextParser<'a> {
             Event::SoftBreak => Ok(RichText::TextBreak(BreakType::SoftLine)),
             Event::HardBreak => Ok(RichText::TextBreak(BreakType::HardLine)),
             Event::Rule => Ok(RichText::TextBreak(BreakType::Rule)),
-            Event::TaskListMarker(_) => todo!("checkbox"),
+            Event::TaskListMarker(checked) => {
+                self.error(RichTextErrorKind::Unsupported("task list marker"));
+                Ok(RichText::RawText(CowStr::Borrowed(if checked {
+                    "[x] "
+                } else {
+                    "[ ] "
+                })))
+            }
         })
     }
 
     fn next_elem(&mut self) -> Option<ControlFlow<EndMarker<'a>, RichText<'a>>> {
+        if let Some(end) = self.pending_end.take() {
+            return Some(ControlFlow::Break(end));
+        }
         match self.next_primitive()? {
             Ok(elem) => Some(ControlFlow::Continue(elem)),
             Err(Event::Start(Tag::HtmlBlock)) | Err(Event::End(TagEnd::HtmlBlock)) => {
@@ -230,16 +428,23 @@ impl<'a> RichTextParser<'a> {
             Err(Event::Start(tag)) => self.handle_tag(tag).map(ControlFlow::Continue),
             Err(Event::InlineHtml(html)) => {
                 if let Some(elem) = html.strip_prefix("</") {
-                    let elem = elem.strip_suffix(">").expect("expected valid xml").trim();
-
-                    Some(ControlFlow::Break(EndMarker::XhtmlTag(
-                        CowStr::Borrowed(elem).into_static(),
-                    )))
+                    match elem.trim_end().strip_suffix(">") {
+                        Some(elem) => Some(ControlFlow::Break(EndMarker::XhtmlTag(
+                            CowStr::Borrowed(elem.trim()).into_static(),
+                        ))),
+                        None => {
+                            self.error(RichTextErrorKind::InvalidXhtml(format!(
+                                "malformed end tag {}",
+                                html.trim_end()
+                            )));
+                            Some(ControlFlow::Continue(RichText::RawText(html.into())))
+                        }
+                    }
                 } else {
                     self.handle_html(html.into()).map(ControlFlow::Continue)
                 }
             }
-            Err(e) => unimplemented!("Non-primitive tag {e:?}"),
+            Err(e) => unreachable!("Non-primitive tag {e:?}"),
         }
     }
 
@@ -254,13 +459,34 @@ impl<'a> RichTextParser<'a> {
         }
     }
 
+    /// Reads the content of a markdown element, up to its end tag.
+    /// Xhtml end tags that do not close an element opened inside of the markdown element are reported and skipped
+    fn read_md_content(&mut self) -> Option<(Vec<RichText<'a>>, TagEnd)> {
+        let mut elems = Vec::new();
+
+        loop {
+            match self.to_end()? {
+                (content, EndMarker::MdTag(end)) => {
+                    elems.extend(content);
+                    break Some((elems, end));
+                }
+                (content, EndMarker::XhtmlTag(tag)) => {
+                    elems.extend(content);
+                    self.error(RichTextErrorKind::UnexpectedEndTag(format!("</{tag}>")));
+                }
+            }
+        }
+    }
+
     fn handle_html(&mut self, blob: CowStr<'a>) -> Option<RichText<'a>> {
-        let mut reader = EventReader::from_str(&blob);
+        let start = self.offset;
+        let wrapped = format!("{}{blob}", xml::INLINE_XHTML_WRAPPER);
+        let mut reader = EventReader::from_str(&wrapped);
 
-        let elem = match reader.next().expect("inline xhtml error") {
-            XmlEvent::StartDocument { .. } => reader.next().expect("inline xhtml error"),
-            e => e,
-        };
+        // The start of the document, and of the wrapper element
+        let _ = reader.next();
+        let _ = reader.next();
+        let elem = reader.next();
 
         let end_name = match reader.next() {
             Ok(XmlEvent::EndElement { name }) => Some(name),
@@ -268,41 +494,106 @@ impl<'a> RichTextParser<'a> {
         };
 
         let elem = match elem {
-            XmlEvent::StartElement {
-                name, attributes, ..
-            } => {
-                let elem = XmlElem {
-                    name: name.to_string(),
+            Ok(XmlEvent::StartElement {
+                name,
+                attributes,
+                namespace,
+            }) => {
+                let mut elem = XmlElem {
+                    name: name.borrow().to_repr(),
                     attrs: attributes
                         .into_iter()
-                        .map(|a| (a.name.to_string(), a.value))
+                        .map(|a| (a.name.borrow().to_repr(), a.value))
                         .collect(),
                 };
+                // Keep the namespaces declared by the element (such as the namespace of `<svg>`), which are not attributes to the parser
+                for (prefix, uri) in &namespace {
+                    let declared_by_wrapper = xml::INLINE_XHTML_NAMESPACES.contains(&(prefix, uri));
+                    match prefix {
+                        _ if declared_by_wrapper => {}
+                        "xml" | "xmlns" => {}
+                        "" if uri.is_empty() => {}
+                        "" => {
+                            elem.attrs.insert("xmlns".to_string(), uri.to_string());
+                        }
+                        prefix => {
+                            elem.attrs
+                                .insert(format!("xmlns:{prefix}"), uri.to_string());
+                        }
+                    }
+                }
                 if let Some(end) = &end_name {
-                    assert_eq!(elem.name, end.to_string(), "Invalid xhtml tag {name:?}");
+                    let end = end.borrow().to_repr();
+                    if elem.name != end {
+                        self.error(RichTextErrorKind::MismatchedEndTag {
+                            expected: elem.name.clone(),
+                            found: end,
+                        });
+                    }
                     return Some(RichText::Xhtml(InlineXhtml::Node(XmlNode::Inline(elem))));
                 }
                 elem
             }
-            e => panic!("Unexpected inline xhtml {e:?}"),
+            Ok(e) => {
+                self.error(RichTextErrorKind::InvalidXhtml(format!(
+                    "expected an element, found {e:?}"
+                )));
+                return Some(RichText::RawText(blob));
+            }
+            Err(e) => {
+                self.error(RichTextErrorKind::InvalidXhtml(e.msg().to_string()));
+                return Some(RichText::RawText(blob));
+            }
         };
         let (elems, end) = self.to_end()?;
 
         match end {
             EndMarker::XhtmlTag(tag) => {
-                assert_eq!(tag, elem.name);
-                Some(RichText::Xhtml(InlineXhtml::Node(XmlNode::Block(
-                    elem, elems,
-                ))))
+                if tag != elem.name {
+                    self.error(RichTextErrorKind::MismatchedEndTag {
+                        expected: elem.name.clone(),
+                        found: tag.to_string(),
+                    });
+                }
+            }
+            end @ EndMarker::MdTag(_) => {
+                self.error_at(start, RichTextErrorKind::UnclosedElement(elem.name.clone()));
+                // The end tag belongs to the markdown element containing this one
+                self.pending_end = Some(end);
+            }
+        }
+        Some(RichText::Xhtml(InlineXhtml::Node(XmlNode::Block(
+            elem, elems,
+        ))))
+    }
+
+    /// Reads the cells of a table row, up to the end of the row. The header row of a table has no separate row tag
+    fn table_row(&mut self) -> Option<TableRow<'a>> {
+        let mut elems = Vec::new();
+        loop {
+            match self.next_event()? {
+                Event::Start(Tag::TableCell) => {
+                    let (content, _) = self.read_md_content()?;
+                    elems.push(TableCell(content))
+                }
+                Event::End(TagEnd::TableHead | TagEnd::TableRow) => break Some(TableRow { elems }),
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
         }
     }
 
+    /// Reports a markdown element that the backends cannot render, and keeps its content without any markup
+    fn unsupported(&mut self, what: &'static str) -> Option<RichText<'a>> {
+        self.error(RichTextErrorKind::Unsupported(what));
+        let (elems, _) = self.read_md_content()?;
+
+        Some(RichText::Stylised(Attributes::default(), elems))
+    }
+
     fn handle_tag(&mut self, tag: Tag<'a>) -> Option<RichText<'a>> {
         match tag {
             Tag::Paragraph => {
-                let (elems, _) = self.to_end()?;
+                let (elems, _) = self.read_md_content()?;
 
                 Some(RichText::Paragraph(elems))
             }
@@ -316,14 +607,8 @@ impl<'a> RichTextParser<'a> {
                     MdHeadingLevel::H6 => HeadingLevel::H6,
                 };
 
-                let mut text = String::new();
-                for i in (&mut self.0).take_while(|r| !matches!(r, Event::End(TagEnd::Heading(_))))
-                {
-                    match i {
-                        Event::Text(t) => text.push_str(&t),
-                        _ => unimplemented!("Rich Text in heading"),
-                    }
-                }
+                let (elems, _) = self.read_md_content()?;
+                let text = plain_text(&elems);
 
                 let id = id.map_or_else(
                     || {
@@ -335,10 +620,15 @@ impl<'a> RichTextParser<'a> {
                 );
 
                 let text = CowStr::Boxed(text.into_boxed_str());
-                Some(RichText::Heading(Heading { level, text, id }))
+                Some(RichText::Heading(Heading {
+                    level,
+                    text,
+                    elems,
+                    id,
+                }))
             }
             Tag::BlockQuote(_) => {
-                let (elems, _) = self.to_end()?;
+                let (elems, _) = self.read_md_content()?;
 
                 Some(RichText::BlockQuote(elems))
             }
@@ -351,7 +641,7 @@ impl<'a> RichTextParser<'a> {
                 let mut text = String::new();
 
                 loop {
-                    match self.0.next()? {
+                    match self.next_event()? {
                         Event::Text(c) => text.push_str(&c),
                         Event::End(TagEnd::CodeBlock) => break,
                         e => unreachable!("Unexpected event {e:?}"),
@@ -368,9 +658,9 @@ impl<'a> RichTextParser<'a> {
                 let style = n.map_or(ListStyle::Unordered, ListStyle::Ordered);
                 let mut elems = Vec::new();
                 loop {
-                    match self.0.next()? {
+                    match self.next_event()? {
                         Event::Start(Tag::Item) => {
-                            let (content, _) = self.to_end()?;
+                            let (content, _) = self.read_md_content()?;
                             elems.push(ListItem(content))
                         }
                         Event::End(TagEnd::List(_)) => {
@@ -384,14 +674,47 @@ impl<'a> RichTextParser<'a> {
                 }
             }
             Tag::Item => unreachable!(),
-            Tag::FootnoteDefinition(cow_str) => todo!(),
-            Tag::DefinitionList => todo!(),
-            Tag::DefinitionListTitle => todo!(),
-            Tag::DefinitionListDefinition => todo!(),
-            Tag::Table(_) => todo!(),
-            Tag::TableHead => todo!(),
-            Tag::TableRow => todo!(),
-            Tag::TableCell => todo!(),
+            Tag::FootnoteDefinition(label) => {
+                let (content, _) = self.read_md_content()?;
+
+                Some(RichText::FootnoteDefinition(FootnoteDefinition {
+                    label: label.into(),
+                    content,
+                }))
+            }
+            Tag::DefinitionList => self.unsupported("definition list"),
+            // Only found inside of a definition list, which is already reported
+            Tag::DefinitionListTitle | Tag::DefinitionListDefinition => {
+                let (elems, _) = self.read_md_content()?;
+
+                Some(RichText::Stylised(Attributes::default(), elems))
+            }
+            Tag::Table(align) => {
+                let align = align
+                    .into_iter()
+                    .map(|align| match align {
+                        MdAlignment::None => Alignment::None,
+                        MdAlignment::Left => Alignment::Left,
+                        MdAlignment::Center => Alignment::Center,
+                        MdAlignment::Right => Alignment::Right,
+                    })
+                    .collect();
+                let mut head = None;
+                let mut body = Vec::new();
+                loop {
+                    match self.next_event()? {
+                        Event::Start(Tag::TableHead) => head = Some(self.table_row()?),
+                        Event::Start(Tag::TableRow) => body.push(self.table_row()?),
+                        Event::End(TagEnd::Table) => {
+                            break Some(RichText::Table(Table { align, head, body }))
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            Tag::TableHead => unreachable!(),
+            Tag::TableRow => unreachable!(),
+            Tag::TableCell => unreachable!(),
             Tag::Link {
                 link_type,
                 dest_url,
@@ -399,7 +722,7 @@ impl<'a> RichTextParser<'a> {
                 id,
             } => {
                 let is_internal = dest_url.find("://").is_none();
-                let (elems, _) = self.to_end()?;
+                let (elems, _) = self.read_md_content()?;
                 let link = Link::Text {
                     title: title.into(),
                     elems,
@@ -419,7 +742,7 @@ impl<'a> RichTextParser<'a> {
                 id,
             } => {
                 let is_internal = dest_url.find("://").is_none();
-                let (elems, _) = self.to_end()?;
+                let (elems, _) = self.read_md_content()?;
                 let link = Link::Text {
                     title: title.into(),
                     elems,
@@ -432,18 +755,18 @@ impl<'a> RichTextParser<'a> {
                     Some(RichText::ExternalImage(link))
                 }
             }
-            Tag::MetadataBlock(metadata_block_kind) => unimplemented!("Metadata should not parse"),
+            Tag::MetadataBlock(_) => self.unsupported("metadata block"),
             Tag::Strong | Tag::Emphasis | Tag::Strikethrough => {
-                let (mut elems, end) = self.to_end()?;
+                let (mut elems, end) = self.read_md_content()?;
                 let (mut style, elems) = match &mut elems[..] {
                     [RichText::Stylised(style, elems)] => (*style, core::mem::take(elems)),
                     _ => (Attributes::default(), elems),
                 };
 
                 match end {
-                    EndMarker::MdTag(TagEnd::Strong) => style.bold = true,
-                    EndMarker::MdTag(TagEnd::Emphasis) => style.italics = true,
-                    EndMarker::MdTag(TagEnd::Strikethrough) => style.strikethrough = true,
+                    TagEnd::Strong => style.bold = true,
+                    TagEnd::Emphasis => style.italics = true,
+                    TagEnd::Strikethrough => style.strikethrough = true,
                     _ => unreachable!(),
                 }
 
@@ -457,9 +780,16 @@ impl<'a> Iterator for RichTextParser<'a> {
     type Item = RichText<'a>;
 
     fn next(&mut self) -> Option<RichText<'a>> {
-        match self.next_elem()? {
-            ControlFlow::Continue(elem) => Some(elem),
-            ControlFlow::Break(end) => panic!("Unexpected ending tag {end:?}"),
+        loop {
+            match self.next_elem()? {
+                ControlFlow::Continue(elem) => break Some(elem),
+                ControlFlow::Break(EndMarker::XhtmlTag(tag)) => {
+                    self.error(RichTextErrorKind::UnexpectedEndTag(format!("</{tag}>")))
+                }
+                ControlFlow::Break(EndMarker::MdTag(end)) => {
+                    self.error(RichTextErrorKind::UnexpectedEndTag(format!("{end:?}")))
+                }
+            }
         }
     }
 }
@@ -468,20 +798,96 @@ impl<'a> Iterator for RichTextParser<'a> {
 pub struct BookChapter<'a> {
     pub src_path: Cow<'a, Path>,
     pub dest_path: Cow<'a, Path>,
+    /// The output id of the chapter (see [`helpers::chapter_output_id`])
+    pub id: String,
+    /// Whether the chapter is numbered in `SUMMARY.md`. Prefix and suffix chapters are not numbered
+    pub numbered: bool,
+    /// The language of the chapter, if it differs from the language of the book (see [`helpers::chapter_language`])
+    pub lang: Option<String>,
     pub content: Vec<RichText<'a>>,
 }
 
 impl<'a> BookChapter<'a> {
-    pub fn from_chapter(ch: &'a mdbook::book::Chapter, opts: RichTextOptions) -> Option<Self> {
-        let src_path = ch.source_path.as_ref()?;
-        let dest_path = ch.path.as_ref()?;
-        let content = RichTextParser::new(&ch.content, opts).collect();
+    /// Parses the content of `ch`. Returns `Ok(None)` for draft chapters, which have no source file.
+    ///
+    /// All problems in the chapter are reported together
+    pub fn from_chapter(
+        ch: &'a mdbook::book::Chapter,
+        opts: RichTextOptions,
+    ) -> Result<Option<Self>, RichTextErrors> {
+        let (Some(src_path), Some(dest_path)) = (&ch.source_path, &ch.path) else {
+            return Ok(None);
+        };
+        let mut parser = RichTextParser::new(&ch.content, opts).with_source_path(src_path);
+        let content = parser.by_ref().collect();
+        parser.finish()?;
 
-        Some(Self {
+        Ok(Some(Self {
             src_path: Cow::Borrowed(src_path),
             dest_path: Cow::Borrowed(dest_path),
+            id: helpers::chapter_output_id(ch),
+            numbered: ch.number.is_some(),
+            lang: helpers::chapter_language(ch),
             content,
-        })
+        }))
+    }
+
+    /// The footnote definitions of the chapter, in the order they are first referenced.
+    /// Definitions that are never referenced follow the rest, in the order they are defined.
+    ///
+    /// Footnotes are numbered by their position in this list, starting from 1
+    pub fn footnotes(&self) -> Vec<&FootnoteDefinition<'a>> {
+        let mut refs = Vec::new();
+        let mut defs = Vec::new();
+        for elem in &self.content {
+            elem.walk(&mut |elem| match elem {
+                RichText::InternalLink(Link::Footnote(label)) => refs.push(label),
+                RichText::FootnoteDefinition(def) => defs.push(def),
+                _ => {}
+            });
+        }
+
+        let mut notes = Vec::with_capacity(defs.len());
+        for label in refs {
+            if let Some(pos) = defs.iter().position(|def| def.label == *label) {
+                notes.push(defs.remove(pos));
+            }
+        }
+        notes.extend(defs);
+        notes
+    }
+}
+
+/// The chapters of a book, each parsed once and shared by every output built from the book
+#[derive(Clone, Debug, Default)]
+pub struct ParsedChapters<'a>(HashMap<&'a Path, BookChapter<'a>>);
+
+impl<'a> ParsedChapters<'a> {
+    /// Parses every chapter of `book`.
+    ///
+    /// Every chapter is parsed even if an earlier chapter has errors, so that all of the problems are reported together
+    pub fn parse(
+        book: &'a mdbook::book::Book,
+        opts: RichTextOptions,
+    ) -> Result<Self, RichTextErrors> {
+        let mut errors = RichTextErrors::default();
+        let mut chapters = HashMap::new();
+        for item in book.iter() {
+            if let BookItem::Chapter(ch) = item {
+                if let (Some(path), Some(Some(chapter))) =
+                    (&ch.path, errors.record(BookChapter::from_chapter(ch, opts)))
+                {
+                    chapters.insert(&**path, chapter);
+                }
+            }
+        }
+
+        errors.into_result(Self(chapters))
+    }
+
+    /// Returns the parsed content of `ch`, or `None` for a draft chapter
+    pub fn get(&self, ch: &mdbook::book::Chapter) -> Option<&BookChapter<'a>> {
+        self.0.get(ch.path.as_deref()?)
     }
 }
 
@@ -510,23 +916,34 @@ pub struct Book<'a> {
     pub extra_files: &'a [ExtraItem],
     pub authors: &'a [&'a str],
     pub id: &'a str,
+    /// The series this book belongs to, when it is one part of a larger book
+    pub series: Option<Series<'a>>,
+}
+
+/// A series of books, such as the parts of a book built with the `part` output type
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Series<'a> {
+    pub title: &'a str,
+    /// The 1-based position of the book within the series
+    pub position: usize,
 }
 
 impl<'a> Book<'a> {
     pub fn build<A: Borrow<BookItem>>(
         title: &'a str,
         items: &'a [A],
-        opts: RichTextOptions,
+        chapters: &ParsedChapters<'a>,
         extra_files: &'a [ExtraItem],
         authors: &'a [&'a str],
         id: &'a str,
     ) -> Book<'a> {
         Book {
             title,
-            tree: NavTree::from_items(items, opts),
+            tree: NavTree::from_items(items, chapters),
             extra_files,
             authors,
             id,
+            series: None,
         }
     }
 }
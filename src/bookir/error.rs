@@ -0,0 +1,138 @@
+use std::{io, path::PathBuf};
+
+/// The kinds of problems that can be found in the markdown source of a chapter
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RichTextErrorKind {
+    /// Inline xhtml that could not be parsed
+    InvalidXhtml(String),
+    /// An xhtml element that is still open at the end of the markdown element containing it
+    UnclosedElement(String),
+    /// An xhtml end tag that does not match the element that is open
+    MismatchedEndTag { expected: String, found: String },
+    /// An end tag that does not close any open element
+    UnexpectedEndTag(String),
+    /// A comment or CDATA section that is never terminated
+    Unterminated(&'static str),
+    /// Markdown that is valid, but not supported by the backends
+    Unsupported(&'static str),
+}
+
+impl core::fmt::Display for RichTextErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidXhtml(msg) => f.write_fmt(format_args!("invalid inline xhtml: {msg}")),
+            Self::UnclosedElement(name) => f.write_fmt(format_args!("<{name}> is never closed")),
+            Self::MismatchedEndTag { expected, found } => {
+                f.write_fmt(format_args!("expected </{expected}>, but found </{found}>"))
+            }
+            Self::UnexpectedEndTag(tag) => f.write_fmt(format_args!("unexpected end tag {tag}")),
+            Self::Unterminated(what) => f.write_fmt(format_args!("unterminated {what}")),
+            Self::Unsupported(what) => f.write_fmt(format_args!("unsupported {what}")),
+        }
+    }
+}
+
+/// A problem in the markdown source of a chapter.
+///
+/// The position is that of the markdown event being parsed when the problem was found,
+/// within the chapter content as given to the renderer (after any preprocessors have run)
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct RichTextError {
+    /// The source file of the chapter, relative to the source directory of the book.
+    /// `None` for text that does not come from a chapter
+    pub path: Option<PathBuf>,
+    /// The byte offset of the problem in the chapter content
+    pub offset: usize,
+    /// The 1-based line of `offset`
+    pub line: usize,
+    /// The 1-based column of `offset`, in characters
+    pub column: usize,
+    pub kind: RichTextErrorKind,
+}
+
+impl RichTextError {
+    pub fn new(
+        path: Option<PathBuf>,
+        source: &str,
+        offset: usize,
+        kind: RichTextErrorKind,
+    ) -> Self {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+
+        Self {
+            path,
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            kind,
+        }
+    }
+}
+
+impl core::fmt::Display for RichTextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => f.write_fmt(format_args!("{}", path.display()))?,
+            None => f.write_str("<input>")?,
+        }
+        f.write_fmt(format_args!(
+            ":{}:{}: {}",
+            self.line, self.column, self.kind
+        ))
+    }
+}
+
+impl std::error::Error for RichTextError {}
+
+/// Every problem found while parsing one or more chapters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RichTextErrors(pub Vec<RichTextError>);
+
+impl RichTextErrors {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Keeps the errors of `res`, if any, so that parsing can continue with the remaining chapters
+    pub fn record<T>(&mut self, res: Result<T, RichTextErrors>) -> Option<T> {
+        match res {
+            Ok(val) => Some(val),
+            Err(errors) => {
+                self.0.extend(errors.0);
+                None
+            }
+        }
+    }
+
+    /// Returns `val`, or every recorded error if there are any
+    pub fn into_result<T>(self, val: T) -> Result<T, RichTextErrors> {
+        if self.is_empty() {
+            Ok(val)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl core::fmt::Display for RichTextErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.len() {
+            1 => f.write_str("1 error in the book")?,
+            n => f.write_fmt(format_args!("{n} errors in the book"))?,
+        }
+        for error in &self.0 {
+            f.write_fmt(format_args!("\nerror: {error}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RichTextErrors {}
+
+impl From<RichTextErrors> for io::Error {
+    fn from(errors: RichTextErrors) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, errors)
+    }
+}
@@ -2,13 +2,15 @@ use std::borrow::{Borrow, Cow};
 use std::path::PathBuf;
 use std::thread::current;
 
+use mdbook::book::Chapter;
 use mdbook::BookItem;
 use serde::{Deserialize, Serialize};
 use xml::name::Name;
 use xml::namespace::Namespace;
 use xml::writer::{EventWriter, XmlEvent};
 
-use super::{BookChapter, CowStr, RichTextOptions};
+use super::{BookChapter, CowStr, ParsedChapters};
+use crate::helpers::display_title;
 
 #[derive(Clone, Debug, Serialize)]
 pub struct NavTree<'a>(Vec<NavNode<'a>>);
@@ -26,31 +28,26 @@ impl<'a> NavTree<'a> {
         self.0.extend(tree.0);
     }
 
-    pub fn from_items<A: Borrow<BookItem>>(items: &'a [A], opts: RichTextOptions) -> Self {
+    /// Builds the tree for `items` and their sub-chapters, from the chapters parsed in `chapters`
+    pub fn from_items<A: Borrow<BookItem>>(items: &'a [A], chapters: &ParsedChapters<'a>) -> Self {
         let mut node = Vec::new();
         let mut current_part = Vec::new();
         let mut part_title = None::<&str>;
         for item in items {
             match item.borrow() {
                 BookItem::Chapter(ch) => {
-                    let content = BookChapter::from_chapter(ch, opts);
-
-                    let children = if ch.sub_items.is_empty() {
-                        None
-                    } else {
-                        Some(Self::from_items(&ch.sub_items, opts))
-                    };
-
-                    let heading = match content {
-                        Some(chapter) => NavHeading::Chapter(CowStr::Borrowed(&ch.name), chapter),
-                        None => NavHeading::UnboundChapter(CowStr::Borrowed(&ch.name)),
-                    };
-                    current_part.push(NavNode { heading, children })
+                    let mut node = NavNode::from_chapter(ch, chapters);
+                    if !ch.sub_items.is_empty() {
+                        node.children = Some(Self::from_items(&ch.sub_items, chapters));
+                    }
+                    current_part.push(node)
                 }
                 BookItem::PartTitle(title) => {
                     if let Some(part_title) = part_title.replace(title) {
                         node.push(NavNode {
-                            heading: NavHeading::Heading(CowStr::Borrowed(part_title)),
+                            heading: NavHeading::Heading(CowStr::Borrowed(display_title(
+                                part_title,
+                            ))),
                             children: Some(NavTree::from_vec(core::mem::take(&mut current_part))),
                         });
                     } else {
@@ -60,7 +57,9 @@ impl<'a> NavTree<'a> {
                 BookItem::Separator => {
                     if let Some(part_title) = part_title.take() {
                         node.push(NavNode {
-                            heading: NavHeading::Heading(CowStr::Borrowed(part_title)),
+                            heading: NavHeading::Heading(CowStr::Borrowed(display_title(
+                                part_title,
+                            ))),
                             children: Some(NavTree::from_vec(core::mem::take(&mut current_part))),
                         });
                     }
@@ -70,7 +69,7 @@ impl<'a> NavTree<'a> {
 
         if let Some(part_title) = part_title.take() {
             node.push(NavNode {
-                heading: NavHeading::Heading(CowStr::Borrowed(part_title)),
+                heading: NavHeading::Heading(CowStr::Borrowed(display_title(part_title))),
                 children: Some(NavTree::from_vec(core::mem::take(&mut current_part))),
             });
         } else {
@@ -142,6 +141,23 @@ pub struct NavNode<'a> {
     pub children: Option<NavTree<'a>>,
 }
 
+impl<'a> NavNode<'a> {
+    /// Builds a node for `ch` alone, without descending into its sub-chapters
+    pub fn from_chapter(ch: &'a Chapter, chapters: &ParsedChapters<'a>) -> Self {
+        let heading = match chapters.get(ch) {
+            Some(chapter) => {
+                NavHeading::Chapter(CowStr::Borrowed(display_title(&ch.name)), chapter.clone())
+            }
+            None => NavHeading::UnboundChapter(CowStr::Borrowed(display_title(&ch.name))),
+        };
+
+        Self {
+            heading,
+            children: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub enum NavHeading<'a> {
     Chapter(CowStr<'a>, BookChapter<'a>),
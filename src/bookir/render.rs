@@ -1 +1,388 @@
+use std::{collections::HashSet, ops::Deref};
 
+use super::{
+    nav::{NavHeading, NavTree},
+    Alignment, Attributes, BookChapter, BreakType, CodeBlock, FootnoteDefinition, Heading,
+    InlineXhtml, Link, List, ListItem, RichText, Table, TableCell, TableRow, XmlNode,
+};
+use crate::helpers;
+
+/// What a link or image in a chapter refers to. Paths are relative to the book source directory, with `/` separators
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkDestination<'u> {
+    /// A chapter of the book. Links to a fragment of the chapter containing them (`#fragment`) refer to that chapter
+    Chapter {
+        path: String,
+        fragment: Option<&'u str>,
+    },
+    /// A file of the book source other than a chapter, such as an image
+    File {
+        path: String,
+        fragment: Option<&'u str>,
+    },
+    /// Something other than a file of the book source, such as a url with a scheme (`https:`, `mailto:`) or a path outside of the book source.
+    /// Holds the link as it is written
+    External(&'u str),
+}
+
+/// Resolves `dest_url`, a link or image in the chapter at `source_path`, to what it refers to
+pub fn resolve_link<'u>(source_path: &str, dest_url: &'u str) -> LinkDestination<'u> {
+    let (path, fragment) = match dest_url.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (dest_url, None),
+    };
+
+    if path.is_empty() {
+        return LinkDestination::Chapter {
+            path: source_path.to_string(),
+            fragment,
+        };
+    }
+
+    match helpers::resolve_source_href(source_path, path) {
+        Some(path) if path.ends_with(".md") => LinkDestination::Chapter { path, fragment },
+        Some(path) => LinkDestination::File { path, fragment },
+        None => LinkDestination::External(dest_url),
+    }
+}
+
+/// The footnotes of a chapter, numbered from 1 in the order they are first referenced (see [`BookChapter::footnotes`]).
+///
+/// Also tracks which footnotes have been referenced, as only the first reference is linked back to from the footnote
+#[derive(Clone, Debug, Default)]
+pub struct Footnotes<'r> {
+    defs: Vec<&'r FootnoteDefinition<'r>>,
+    referenced: HashSet<String>,
+}
+
+impl<'r> Footnotes<'r> {
+    pub fn new(chapter: &'r BookChapter<'r>) -> Self {
+        Self {
+            defs: chapter.footnotes(),
+            referenced: HashSet::new(),
+        }
+    }
+
+    /// The number shown for the footnote with `label`
+    pub fn number(&self, label: &str) -> Option<usize> {
+        self.defs
+            .iter()
+            .position(|def| *def.label == *label)
+            .map(|idx| idx + 1)
+    }
+
+    /// Records a reference to the footnote with `label`. Returns whether it is the first
+    pub fn reference(&mut self, label: &str) -> bool {
+        self.referenced.insert(label.to_string())
+    }
+}
+
+impl<'r> Deref for Footnotes<'r> {
+    type Target = [&'r FootnoteDefinition<'r>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.defs
+    }
+}
+
+/// Renders the content of a book into an output format, with a hook for each kind of element.
+///
+/// Hooks for elements that contain other elements (such as [`Renderer::paragraph`]) render their content by default,
+/// and most leaf elements are rendered as text, so an output format only implements [`Renderer::text`] and the hooks for the elements it represents.
+/// Footnote definitions are skipped where they appear, to be rendered where the format places them with [`Renderer::render_footnotes`].
+///
+/// Internal links and images can be resolved with [`resolve_link`]
+pub trait Renderer<'a> {
+    type Error;
+
+    /// Renders `elem` by calling the hook for its kind
+    fn render(&mut self, elem: &RichText<'a>) -> Result<(), Self::Error> {
+        match elem {
+            RichText::RawText(text) => self.text(text),
+            RichText::Xhtml(InlineXhtml::Node(node)) => self.xhtml_node(node),
+            RichText::Xhtml(InlineXhtml::Comment(comment)) => self.xhtml_comment(comment),
+            RichText::Xhtml(InlineXhtml::CData(text)) => self.xhtml_cdata(text),
+            RichText::Stylised(attrs, elems) => self.stylised(attrs, elems),
+            RichText::Paragraph(elems) => self.paragraph(elems),
+            RichText::InlineCode(code) => self.inline_code(code),
+            RichText::CodeBlock(code) => self.code_block(code),
+            RichText::BlockQuote(elems) => self.block_quote(elems),
+            RichText::InternalLink(Link::Text {
+                title,
+                elems,
+                dest_url,
+            }) => self.internal_link(title, elems, dest_url),
+            RichText::ExternalLink(Link::Text {
+                title,
+                elems,
+                dest_url,
+            }) => self.external_link(title, elems, dest_url),
+            RichText::InternalLink(Link::Footnote(label))
+            | RichText::ExternalLink(Link::Footnote(label)) => self.footnote_reference(label),
+            RichText::InternalImage(Link::Text {
+                title,
+                elems,
+                dest_url,
+            }) => self.internal_image(title, elems, dest_url),
+            RichText::ExternalImage(Link::Text {
+                title,
+                elems,
+                dest_url,
+            }) => self.external_image(title, elems, dest_url),
+            // Images cannot refer to footnotes
+            RichText::InternalImage(Link::Footnote(_))
+            | RichText::ExternalImage(Link::Footnote(_)) => Ok(()),
+            RichText::Heading(heading) => self.heading(heading),
+            RichText::TextBreak(kind) => self.text_break(*kind),
+            RichText::List(list) => self.list(list),
+            RichText::Table(table) => self.table(table),
+            RichText::FootnoteDefinition(def) => self.footnote_definition(def),
+            #[cfg(feature = "math")]
+            RichText::MathBlock(math) | RichText::InlineMath(math) => self.math(math),
+        }
+    }
+
+    fn render_all(&mut self, elems: &[RichText<'a>]) -> Result<(), Self::Error> {
+        for elem in elems {
+            self.render(elem)?;
+        }
+        Ok(())
+    }
+
+    /// Renders every chapter in `tree`, in order. Chapters nested under another chapter are rendered after it, and the chapters of a part between [`Renderer::start_part`] and [`Renderer::end_part`]
+    fn render_tree(&mut self, tree: &'a NavTree<'a>) -> Result<(), Self::Error> {
+        for node in tree {
+            match &node.heading {
+                NavHeading::Chapter(title, chapter) => self.render_chapter(title, chapter)?,
+                NavHeading::Heading(title) => self.start_part(title)?,
+                NavHeading::UnboundChapter(title) => self.draft_chapter(title)?,
+            }
+            if let Some(children) = &node.children {
+                self.render_tree(children)?;
+            }
+            if let NavHeading::Heading(title) = &node.heading {
+                self.end_part(title)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders a chapter, between [`Renderer::start_chapter`] and [`Renderer::end_chapter`]. `title` is the title of the chapter in the table of contents
+    fn render_chapter(
+        &mut self,
+        title: &str,
+        chapter: &'a BookChapter<'a>,
+    ) -> Result<(), Self::Error> {
+        self.start_chapter(title, chapter)?;
+        self.render_all(&chapter.content)?;
+        self.end_chapter(title, chapter)
+    }
+
+    /// Renders each footnote in `notes` with [`Renderer::footnote`], in the order they are numbered
+    fn render_footnotes(&mut self, notes: &Footnotes<'a>) -> Result<(), Self::Error> {
+        for (idx, def) in notes.iter().enumerate() {
+            self.footnote(idx + 1, def)?;
+        }
+        Ok(())
+    }
+
+    fn start_chapter(
+        &mut self,
+        title: &str,
+        chapter: &'a BookChapter<'a>,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, chapter);
+        Ok(())
+    }
+
+    fn end_chapter(
+        &mut self,
+        title: &str,
+        chapter: &'a BookChapter<'a>,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, chapter);
+        Ok(())
+    }
+
+    /// Starts a part of the book, which is a title in `SUMMARY.md` followed by the chapters of the part
+    fn start_part(&mut self, title: &str) -> Result<(), Self::Error> {
+        let _ = title;
+        Ok(())
+    }
+
+    fn end_part(&mut self, title: &str) -> Result<(), Self::Error> {
+        let _ = title;
+        Ok(())
+    }
+
+    /// A draft chapter, which has a title in `SUMMARY.md` but no content
+    fn draft_chapter(&mut self, title: &str) -> Result<(), Self::Error> {
+        let _ = title;
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), Self::Error>;
+
+    /// Inline xhtml elements. The content of block elements is rendered by default
+    fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Self::Error> {
+        match node {
+            XmlNode::Block(_, content) => self.render_all(content),
+            XmlNode::Inline(_) => Ok(()),
+        }
+    }
+
+    fn xhtml_comment(&mut self, comment: &str) -> Result<(), Self::Error> {
+        let _ = comment;
+        Ok(())
+    }
+
+    fn xhtml_cdata(&mut self, text: &str) -> Result<(), Self::Error> {
+        self.text(text)
+    }
+
+    fn stylised(&mut self, attrs: &Attributes, elems: &[RichText<'a>]) -> Result<(), Self::Error> {
+        let _ = attrs;
+        self.render_all(elems)
+    }
+
+    fn paragraph(&mut self, elems: &[RichText<'a>]) -> Result<(), Self::Error> {
+        self.render_all(elems)
+    }
+
+    fn inline_code(&mut self, code: &str) -> Result<(), Self::Error> {
+        self.text(code)
+    }
+
+    fn code_block(&mut self, code: &CodeBlock<'a>) -> Result<(), Self::Error> {
+        self.text(&code.content)
+    }
+
+    fn block_quote(&mut self, elems: &[RichText<'a>]) -> Result<(), Self::Error> {
+        self.render_all(elems)
+    }
+
+    /// A link to a chapter or file of the book, or within the chapter (see [`resolve_link`])
+    fn internal_link(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, dest_url);
+        self.render_all(elems)
+    }
+
+    fn external_link(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, dest_url);
+        self.render_all(elems)
+    }
+
+    /// A reference to the footnote with `label`, which is numbered by [`Footnotes::number`]
+    fn footnote_reference(&mut self, label: &str) -> Result<(), Self::Error> {
+        let _ = label;
+        Ok(())
+    }
+
+    /// An image in the book source. `elems` are the alternative text of the image, which is rendered by default
+    fn internal_image(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, dest_url);
+        self.render_all(elems)
+    }
+
+    fn external_image(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Self::Error> {
+        let _ = (title, dest_url);
+        self.render_all(elems)
+    }
+
+    fn heading(&mut self, heading: &Heading<'a>) -> Result<(), Self::Error> {
+        self.render_all(&heading.elems)
+    }
+
+    /// Line breaks are rendered as whitespace by default, and rules are skipped
+    fn text_break(&mut self, kind: BreakType) -> Result<(), Self::Error> {
+        match kind {
+            BreakType::SoftLine => self.text(" "),
+            BreakType::HardLine => self.text("\n"),
+            BreakType::Rule => Ok(()),
+        }
+    }
+
+    fn list(&mut self, list: &List<'a>) -> Result<(), Self::Error> {
+        for item in &list.elems {
+            self.list_item(item)?;
+        }
+        Ok(())
+    }
+
+    fn list_item(&mut self, item: &ListItem<'a>) -> Result<(), Self::Error> {
+        self.render_all(&item.0)
+    }
+
+    fn table(&mut self, table: &Table<'a>) -> Result<(), Self::Error> {
+        if let Some(head) = &table.head {
+            self.table_row(head, true, &table.align)?;
+        }
+        for row in &table.body {
+            self.table_row(row, false, &table.align)?;
+        }
+        Ok(())
+    }
+
+    /// A row of a table, which is the heading row if `head` is set. `align` is the alignment of each column
+    fn table_row(
+        &mut self,
+        row: &TableRow<'a>,
+        head: bool,
+        align: &[Alignment],
+    ) -> Result<(), Self::Error> {
+        for (idx, cell) in row.elems.iter().enumerate() {
+            let align = align.get(idx).copied().unwrap_or(Alignment::None);
+            self.table_cell(cell, head, align)?;
+        }
+        Ok(())
+    }
+
+    fn table_cell(
+        &mut self,
+        cell: &TableCell<'a>,
+        head: bool,
+        align: Alignment,
+    ) -> Result<(), Self::Error> {
+        let _ = (head, align);
+        self.render_all(&cell.0)
+    }
+
+    /// A footnote definition, where it appears in the chapter. Skipped by default
+    fn footnote_definition(&mut self, def: &FootnoteDefinition<'a>) -> Result<(), Self::Error> {
+        let _ = def;
+        Ok(())
+    }
+
+    /// Math is skipped by default
+    #[cfg(feature = "math")]
+    fn math(&mut self, math: &super::math::Math<'a>) -> Result<(), Self::Error> {
+        let _ = math;
+        Ok(())
+    }
+
+    /// The footnote numbered `number`, placed by [`Renderer::render_footnotes`]
+    fn footnote(&mut self, number: usize, def: &FootnoteDefinition<'a>) -> Result<(), Self::Error> {
+        let _ = number;
+        self.render_all(&def.content)
+    }
+}
@@ -2,6 +2,14 @@ use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use xml::{attribute::Attribute, name::Name, namespace::Namespace, writer::XmlEvent};
 
+/// The element that inline xhtml is parsed inside of, which declares the namespace prefixes that chapters can use without declaring them
+pub(crate) const INLINE_XHTML_WRAPPER: &str =
+    r#"<mdbook-inline-xhtml xmlns:epub="http://www.idpf.org/2007/ops">"#;
+
+/// The namespaces declared by [`INLINE_XHTML_WRAPPER`]
+pub(crate) const INLINE_XHTML_NAMESPACES: &[(&str, &str)] =
+    &[("epub", "http://www.idpf.org/2007/ops")];
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct XmlElem {
     pub name: String,
@@ -8,6 +8,8 @@ use std::{
 use serde_derive::Deserialize;
 use uuid::Uuid;
 
+use crate::Output;
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum SerList<T> {
@@ -209,6 +211,9 @@ pub struct SharedConfig {
     pub content_types: HashMap<PathBuf, String>,
     #[cfg(feature = "math")]
     pub math_support: bool,
+    /// The bundled theme that code blocks are highlighted with (see [`crate::highlight::find_theme`]). Code blocks are not highlighted if this is not set
+    #[cfg(feature = "highlight")]
+    pub highlight_theme: Option<String>,
     #[doc(hidden)]
     pub __non_exhaustive: (),
 }
@@ -271,3 +276,14 @@ impl<Id> Default for FileIds<Id> {
         }
     }
 }
+
+impl<Id> FileIds<Id> {
+    /// Gets the entry for the given output, if one is specified
+    pub fn get(&self, output: &Output) -> Option<&Id> {
+        match output {
+            Output::Full => self.full.as_ref(),
+            Output::Part(id) | Output::Chapter(id) => self.individual_files.get(id),
+            _ => None,
+        }
+    }
+}
@@ -1,100 +1,697 @@
 use std::{
     borrow::Cow,
+    collections::HashSet,
+    convert::Infallible,
     path::{Path, PathBuf},
 };
 
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
 use xml::{
     name::Name,
     namespace::{Namespace, NS_NO_PREFIX, NS_XML_PREFIX, NS_XML_URI},
     writer::XmlEvent,
     EmitterConfig, EventWriter,
 };
-use zip::write::{FileOptions, ZipWriter};
+use zip::write::FileOptions;
 
 use crate::{
     bookir::{
         nav::{NavHeading, NavTree},
-        Book,
+        plain_text,
+        render::Renderer,
+        Book, BookChapter, ExtraItem, FootnoteDefinition, RichText, XmlNode,
     },
     epub::{
+        config::{EpubConfig, NavConfig, NavPlacement},
+        container::ContainerWriter,
         info::NS_CONTAINER_URI,
         package::{ItemProperty, ManifestItem, EPUB_PACKAGE_MEDIA_TYPE},
     },
-    helpers::{media_type_from_file, name_to_id, visit_chapters},
+    helpers::{self, media_type_from_file, normalize_path, visit_chapters},
+    Output,
 };
 
 use info::EpubFileInfo;
 
+pub mod check;
 pub mod config;
+pub mod container;
 pub mod info;
+pub mod ncx;
 pub mod package;
 #[cfg(feature = "epub-signatures")]
 pub mod signature;
 pub mod style;
-use crate::xhtml;
+use crate::xhtml::{self, DocumentContext, FootnotePlacement, SectionType};
+
+pub use crate::xhtml::{NS_EPUB_PREFIX, NS_EPUB_URI};
+
+/// The default path of the document generated for the cover image, which moves aside for a chapter or resource at the same path
+pub const COVER_DOCUMENT: &str = "cover.xhtml";
+
+/// The default path of the navigation document, which moves aside for a chapter or resource at the same path
+pub const NAV_DOCUMENT: &str = "nav.xhtml";
+
+/// The id of the `toc` nav in the navigation document
+const NAV_TOC_ID: &str = "toc";
+
+/// The manifest ids of the documents and stylesheets that are not chapters
+const RESERVED_IDS: &[&str] = &[
+    "default-style",
+    "highlight-style",
+    "cover",
+    "cover-image",
+    "notes",
+    "nav-toc",
+    ncx::NCX_ID,
+];
+
+/// Makes the manifest id `id` unique among the ids in `used`, by appending `-2`, `-3`, and so on, and adds it to `used`
+fn unique_id(id: &str, used: &mut HashSet<String>) -> String {
+    let mut unique = id.to_string();
+    let mut n = 1;
+    while !used.insert(unique.clone()) {
+        n += 1;
+        unique = format!("{id}-{n}");
+    }
+    unique
+}
 
-pub const NS_EPUB_PREFIX: &str = "epub";
-pub const NS_EPUB_URI: &str = "http://www.idpf.org/2007/ops";
+/// Makes the path `path` of a generated document unique among the paths in `used`, by appending `-2`, `-3`, and so on to its name, and adds it to `used`.
+///
+/// Chapters and resources can have any path, so the documents generated alongside them cannot use fixed paths
+fn unique_path(path: &str, used: &mut HashSet<String>) -> String {
+    let (stem, extension) = path.rsplit_once('.').unwrap_or((path, ""));
+    let mut unique = path.to_string();
+    let mut n = 1;
+    while !used.insert(unique.clone()) {
+        n += 1;
+        unique = format!("{stem}-{n}.{extension}");
+    }
+    unique
+}
 
+/// Writes the list of the `toc` nav for `tree`, with at most `depth` levels (or every level if `depth` is `None`)
 pub fn write_nav<W: std::io::Write>(
     tree: &NavTree,
+    depth: Option<usize>,
     w: &mut EventWriter<W>,
 ) -> xml::writer::Result<()> {
-    w.write(XmlEvent::start_element("ol"))?;
+    xhtml::write_nav_list(tree, depth, &chapter_href, w)
+}
 
-    for node in tree {
-        match &node.heading {
-            NavHeading::Chapter(title, chapter) => {
-                let mut path = chapter.dest_path.to_path_buf();
-                path.set_extension("xhtml");
+/// The path of the document of `chapter`, relative to the root of the container
+fn chapter_href(chapter: &BookChapter) -> String {
+    let mut path = chapter.dest_path.to_path_buf();
+    path.set_extension("xhtml");
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Finds the page break markers (inline xhtml with `epub:type="pagebreak"` and an `id`) in `chapter`.
+///
+/// Returns the id and the page number of each marker. The page number is the `title` or `aria-label` of the marker, or otherwise its text
+fn page_breaks(chapter: &BookChapter) -> Vec<(String, String)> {
+    #[derive(Default)]
+    struct PageBreaks(Vec<(String, String)>);
 
-                w.write(XmlEvent::start_element("li"))?;
+    impl<'a> Renderer<'a> for PageBreaks {
+        type Error = Infallible;
 
-                w.write(XmlEvent::start_element("a").attr("href", &path.to_string_lossy()))?;
-                w.write(XmlEvent::characters(title))?;
-                w.write(XmlEvent::end_element())?;
+        fn text(&mut self, _: &str) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Infallible> {
+            let (el, content) = match node {
+                XmlNode::Inline(el) => (el, &[][..]),
+                XmlNode::Block(el, content) => (el, &content[..]),
+            };
+            let is_pagebreak = el
+                .attrs
+                .get("epub:type")
+                .is_some_and(|ty| ty.split_whitespace().any(|ty| ty == "pagebreak"));
+            if let Some(id) = el.attrs.get("id").filter(|_| is_pagebreak) {
+                let label = el
+                    .attrs
+                    .get("title")
+                    .or_else(|| el.attrs.get("aria-label"))
+                    .cloned()
+                    .unwrap_or_else(|| plain_text(content));
+                let label = match label.trim() {
+                    "" => id.clone(),
+                    label => label.to_string(),
+                };
+                self.0.push((id.clone(), label));
             }
-            NavHeading::Heading(head) => {
-                w.write(XmlEvent::start_element("li"))?;
-                w.write(XmlEvent::start_element("span"))?;
-                w.write(XmlEvent::characters(head))?;
-                w.write(XmlEvent::end_element())?;
+            self.render_all(content)
+        }
+
+        fn footnote_definition(&mut self, def: &FootnoteDefinition<'a>) -> Result<(), Infallible> {
+            self.render_all(&def.content)
+        }
+    }
+
+    let mut breaks = PageBreaks::default();
+    let Ok(()) = breaks.render_all(&chapter.content);
+    breaks.0
+}
+
+/// The manifest properties found in the content of a document, see [`content_properties`]
+#[derive(Default)]
+struct ContentProperties {
+    mathml: bool,
+    remote: bool,
+    scripted: bool,
+    svg: bool,
+}
+
+impl<'a> Renderer<'a> for ContentProperties {
+    type Error = Infallible;
+
+    fn text(&mut self, _: &str) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Infallible> {
+        let (XmlNode::Inline(el) | XmlNode::Block(el, _)) = node;
+        // Elements may be prefixed, such as `svg:svg`
+        let local_name = el.name.rsplit(':').next().unwrap_or(&el.name);
+        match local_name {
+            "svg" => self.svg = true,
+            "math" => self.mathml = true,
+            "script" | "form" => self.scripted = true,
+            _ => {}
+        }
+        for (name, value) in &el.attrs {
+            let is_resource = match &**name {
+                "src" | "data" | "poster" => true,
+                "href" | "xlink:href" => matches!(local_name, "image" | "use"),
+                _ => false,
+            };
+            if is_resource && (value.starts_with("http://") || value.starts_with("https://")) {
+                self.remote = true;
             }
-            NavHeading::UnboundChapter(title) => {
-                w.write(XmlEvent::start_element("li"))?;
-                w.write(XmlEvent::start_element("span"))?;
-                w.write(XmlEvent::characters(title))?;
-                w.write(XmlEvent::end_element())?;
+            if name.starts_with("on") {
+                self.scripted = true;
             }
         }
-        if let Some(children) = &node.children {
-            write_nav(children, w)?;
+
+        match node {
+            XmlNode::Block(_, content) => self.render_all(content),
+            XmlNode::Inline(_) => Ok(()),
         }
-        w.write(XmlEvent::end_element())?;
     }
-    w.write(XmlEvent::end_element())
+
+    fn external_image(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        _: &str,
+    ) -> Result<(), Infallible> {
+        self.remote = true;
+        self.render_all(elems)
+    }
+
+    fn footnote_definition(&mut self, def: &FootnoteDefinition<'a>) -> Result<(), Infallible> {
+        self.render_all(&def.content)
+    }
+
+    #[cfg(feature = "math")]
+    fn math(&mut self, _: &crate::bookir::math::Math<'a>) -> Result<(), Infallible> {
+        self.mathml = true;
+        Ok(())
+    }
+}
+
+/// Computes the manifest properties required by a document with the content `elems`
+fn content_properties<'r, 'a: 'r>(
+    elems: impl IntoIterator<Item = &'r RichText<'a>>,
+) -> Vec<ItemProperty> {
+    let mut found = ContentProperties::default();
+    for elem in elems {
+        let Ok(()) = found.render(elem);
+    }
+
+    let mut properties = Vec::new();
+    if found.mathml {
+        properties.push(ItemProperty::MathML);
+    }
+    if found.remote {
+        properties.push(ItemProperty::RemoteResources);
+    }
+    if found.scripted {
+        properties.push(ItemProperty::Scripted);
+    }
+    if found.svg {
+        properties.push(ItemProperty::Svg);
+    }
+    properties
+}
+
+/// Collects the chapters that are written as parts: those with other chapters nested under them, which are not nested under a chapter themselves.
+/// Part headings in `SUMMARY.md` have no document, so a part can only be introduced by such a chapter
+fn part_chapters<'t>(tree: &'t NavTree, parts: &mut HashSet<&'t Path>) {
+    for node in tree {
+        match &node.heading {
+            NavHeading::Chapter(_, chapter) => {
+                if node
+                    .children
+                    .iter()
+                    .flat_map(NavTree::nested)
+                    .any(|node| matches!(node.heading, NavHeading::Chapter(..)))
+                {
+                    parts.insert(&chapter.dest_path);
+                }
+            }
+            NavHeading::Heading(_) => {
+                if let Some(children) = &node.children {
+                    part_chapters(children, parts);
+                }
+            }
+            NavHeading::UnboundChapter(_) => {}
+        }
+    }
+}
+
+/// Writes the navigation document, `nav.xhtml`
+fn write_nav_document<W: std::io::Write>(
+    tree: &NavTree,
+    config: &NavConfig,
+    landmarks: &[(&str, String, &str)],
+    page_list: &[(String, String)],
+    stylesheets: &[String],
+    lang: &str,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let cx = DocumentContext::default()
+        .with_stylesheets(stylesheets)
+        .with_lang(lang);
+    xhtml::write_document_start(&cx, w)?;
+    xhtml::write_head(Some(&config.title), &cx, w)?;
+    w.write(XmlEvent::start_element("body"))?;
+    w.write(
+        XmlEvent::start_element("nav")
+            .attr(Name::prefixed("type", NS_EPUB_PREFIX), "toc")
+            .attr("id", NAV_TOC_ID),
+    )?;
+    w.write(XmlEvent::start_element("h1"))?;
+    w.write(XmlEvent::characters(&config.title))?;
+    w.write(XmlEvent::end_element())?; // </h1>
+    write_nav(tree, config.depth, w)?;
+    w.write(XmlEvent::end_element())?; // </nav>
+
+    if !landmarks.is_empty() {
+        w.write(
+            XmlEvent::start_element("nav")
+                .attr(Name::prefixed("type", NS_EPUB_PREFIX), "landmarks")
+                .attr("hidden", ""),
+        )?;
+        w.write(XmlEvent::start_element("ol"))?;
+        for (ty, href, label) in landmarks {
+            w.write(XmlEvent::start_element("li"))?;
+            w.write(
+                XmlEvent::start_element("a")
+                    .attr(Name::prefixed("type", NS_EPUB_PREFIX), ty)
+                    .attr("href", href),
+            )?;
+            w.write(XmlEvent::characters(label))?;
+            w.write(XmlEvent::end_element())?; // </a>
+            w.write(XmlEvent::end_element())?; // </li>
+        }
+        w.write(XmlEvent::end_element())?; // </ol>
+        w.write(XmlEvent::end_element())?; // </nav>
+    }
+
+    if !page_list.is_empty() {
+        w.write(
+            XmlEvent::start_element("nav")
+                .attr(Name::prefixed("type", NS_EPUB_PREFIX), "page-list")
+                .attr("hidden", ""),
+        )?;
+        w.write(XmlEvent::start_element("ol"))?;
+        for (href, label) in page_list {
+            w.write(XmlEvent::start_element("li"))?;
+            w.write(XmlEvent::start_element("a").attr("href", href))?;
+            w.write(XmlEvent::characters(label))?;
+            w.write(XmlEvent::end_element())?; // </a>
+            w.write(XmlEvent::end_element())?; // </li>
+        }
+        w.write(XmlEvent::end_element())?; // </ol>
+        w.write(XmlEvent::end_element())?; // </nav>
+    }
+
+    w.write(XmlEvent::end_element())?; // </body>
+    w.write(XmlEvent::end_element()) // </html>
+}
+
+/// Writes the document that footnotes are collected into with [`FootnotePlacement::EndOfBook`]
+fn write_notes_document<W: std::io::Write>(
+    path: &str,
+    chapters: &mut [(String, DocumentContext)],
+    stylesheets: &[String],
+    lang: &str,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let cx = DocumentContext::default()
+        .with_stylesheets(stylesheets)
+        .with_lang(lang);
+    xhtml::write_document_start(&cx, w)?;
+    xhtml::write_head(Some("Notes"), &cx, w)?;
+    w.write(XmlEvent::start_element("body"))?;
+    w.write(XmlEvent::start_element("h1"))?;
+    w.write(XmlEvent::characters("Notes"))?;
+    w.write(XmlEvent::end_element())?; // </h1>
+
+    for (title, cx) in chapters {
+        if cx.notes.is_empty() {
+            continue;
+        }
+        // Links in the footnotes are now relative to the notes document, which is at the root of the container
+        cx.document_path = path.to_string();
+        w.write(XmlEvent::start_element("h2"))?;
+        w.write(XmlEvent::characters(title))?;
+        w.write(XmlEvent::end_element())?; // </h2>
+        xhtml::write_footnotes(cx, w)?;
+    }
+
+    w.write(XmlEvent::end_element())?; // </body>
+    w.write(XmlEvent::end_element()) // </html>
+}
+
+fn not_in_source(what: &str, path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{what} `{}` is not in the book source", path.display()),
+    )
+}
+
+/// Finds the file at `path` (relative to the book source directory) among the files packaged with the book
+fn find_source_file<'a>(
+    what: &str,
+    path: &Path,
+    extra_files: &'a [ExtraItem],
+) -> std::io::Result<&'a ExtraItem> {
+    let path = normalize_path(path).ok_or_else(|| not_in_source(what, path))?;
+    extra_files
+        .iter()
+        .find(|file| file.dest_path == path)
+        .ok_or_else(|| not_in_source(what, &path))
+}
+
+/// Checks that a user stylesheet, and every font it loads, is packaged with the book.
+///
+/// Returns the path of the stylesheet in the container
+fn check_stylesheet(css: &Path, extra_files: &[ExtraItem]) -> std::io::Result<String> {
+    let file = find_source_file("stylesheet", css, extra_files)?;
+
+    let content = std::fs::read_to_string(&file.src_path)?;
+    let dir = file.dest_path.parent().unwrap_or(Path::new(""));
+    for url in style::font_face_urls(&content) {
+        find_source_file("font", &dir.join(&url), extra_files)?;
+    }
+
+    Ok(file.dest_path.to_string_lossy().replace('\\', "/"))
+}
+
+fn build_globs(globs: &[String]) -> std::io::Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for glob in globs {
+        set.add(
+            GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid resource glob `{glob}`: {e}"),
+                    )
+                })?,
+        );
+    }
+    set.build()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Selects the files of the book source directory that are packaged in the output: the files in `required` and `referenced`,
+/// the fonts of any stylesheets among them, and the files matching `resources.include`, except for those matching `resources.exclude`.
+///
+/// The files in `required` (the stylesheets and the cover image) are packaged even if they are excluded, as the output refers to them outside of the chapters
+fn select_resources<'b>(
+    extra_files: &'b [ExtraItem],
+    required: &HashSet<String>,
+    mut referenced: HashSet<String>,
+    config: &EpubConfig,
+) -> std::io::Result<Vec<&'b ExtraItem>> {
+    let include = build_globs(&config.resources.include)?;
+    let exclude = build_globs(&config.resources.exclude)?;
+    let source_path = |file: &ExtraItem| file.dest_path.to_string_lossy().replace('\\', "/");
+
+    let mut fonts = Vec::new();
+    for file in extra_files {
+        let path = source_path(file);
+        if file.content_type == style::CSS_MEDIA
+            && (required.contains(&path) || referenced.contains(&path) || include.is_match(&path))
+        {
+            let content = std::fs::read_to_string(&file.src_path)?;
+            fonts.extend(
+                style::font_face_urls(&content)
+                    .iter()
+                    .filter_map(|url| helpers::resolve_source_href(&path, url)),
+            );
+        }
+    }
+    referenced.extend(fonts);
+
+    Ok(extra_files
+        .iter()
+        .filter(|file| {
+            let path = source_path(file);
+            required.contains(&path)
+                || (referenced.contains(&path) || include.is_match(&path))
+                    && !exclude.is_match(&path)
+        })
+        .collect())
+}
+
+/// Writes the document displaying the cover image, which is placed at the start of the spine
+fn write_cover_document<W: std::io::Write>(
+    title: &str,
+    image: &str,
+    stylesheets: &[String],
+    lang: &str,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let cx = DocumentContext::default()
+        .with_stylesheets(stylesheets)
+        .with_lang(lang);
+    xhtml::write_document_start(&cx, w)?;
+    xhtml::write_head(Some(title), &cx, w)?;
+    w.write(XmlEvent::start_element("body").attr("class", "cover"))?;
+    w.write(
+        XmlEvent::start_element("section")
+            .attr(Name::prefixed("type", NS_EPUB_PREFIX), "cover")
+            .attr("class", "cover"),
+    )?;
+    w.write(
+        XmlEvent::start_element("img")
+            .attr("src", image)
+            .attr("alt", title),
+    )?;
+    w.write(XmlEvent::end_element())?; // </img>
+    w.write(XmlEvent::end_element())?; // </section>
+    w.write(XmlEvent::end_element())?; // </body>
+    w.write(XmlEvent::end_element()) // </html>
+}
+
+/// The output that an epub is written for, and where its files come from and go besides the book itself
+#[derive(Copy, Clone, Debug)]
+pub struct OutputContext<'a> {
+    /// The output of the book, which selects its cover image
+    pub output: &'a Output,
+    /// The directory that the files of the epub are also written into with `save-temps` (see [`container::exploded_dir`])
+    pub exploded_dir: Option<&'a Path>,
+    /// The root directory of the book, which paths in the configuration (such as the signing key) are relative to
+    pub root: &'a Path,
 }
 
 pub fn write_epub<W: std::io::Write + std::io::Seek>(
     writer: W,
     book: Book,
     info: EpubFileInfo,
-    package_id: String,
+    config: &EpubConfig,
+    cx: &OutputContext,
 ) -> std::io::Result<()> {
     use std::io::Write;
-    let zip_file_options = FileOptions::default();
+    config.check_features()?;
+    // Zip timestamps range from 1980 to 2107, so times outside of that range (such as `SOURCE_DATE_EPOCH=0`) are clamped to its nearest end
+    let modified = info.modified.to_offset(time::UtcOffset::UTC);
+    let modified = match zip::DateTime::try_from(modified) {
+        Ok(modified) => modified,
+        Err(_) if modified.year() < 1980 => zip::DateTime::default(),
+        Err(_) => zip::DateTime::from_date_and_time(2107, 12, 31, 23, 59, 58)
+            .expect("the end of 2107 is a valid zip timestamp"),
+    };
+    let zip_file_options = FileOptions::default().last_modified_time(modified);
     let xml_config = EmitterConfig::new();
-    let mut zip = ZipWriter::new(writer);
+    let mut zip = ContainerWriter::new(writer, cx.exploded_dir)?;
 
     zip.set_comment(&info.title);
 
     zip.start_file(
         "mimetype",
-        FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+        zip_file_options.compression_method(zip::CompressionMethod::Stored),
     )?;
     write!(zip, "application/epub+zip")?;
 
+    // Everything after the mimetype is signed
+    #[cfg(feature = "epub-signatures")]
+    let signing_key = config
+        .signing_key
+        .as_ref()
+        .map(|pair| signature::SigningKey::load(pair, cx.root))
+        .transpose()?;
+    #[cfg(feature = "epub-signatures")]
+    if signing_key.is_some() {
+        zip.record_digests();
+    }
+
     let mut manifest = Vec::new();
+    // Chapter ids come from the book, so they are kept apart from the fixed ids and from each other
+    let mut used_ids = RESERVED_IDS
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<HashSet<_>>();
+    let mut notes = Vec::new();
+    let mut stylesheets = Vec::new();
+
+    if config.default_css {
+        zip.start_file(style::DEFAULT_STYLE_PATH, zip_file_options)?;
+        zip.write_all(style::DEFAULT_STYLE)?;
+
+        manifest.push(ManifestItem {
+            id: "default-style".to_string(),
+            path: PathBuf::from(style::DEFAULT_STYLE_PATH),
+            media_type: Cow::Borrowed(style::CSS_MEDIA),
+            properties: vec![],
+            fallback: None,
+            spine: false,
+        });
+        stylesheets.push(style::DEFAULT_STYLE_PATH.to_string());
+    }
+
+    // The theme is linked before the book's own stylesheets, so that they can override it
+    #[cfg(feature = "highlight")]
+    let highlight_theme = config
+        .highlight_theme
+        .as_deref()
+        .map(crate::highlight::find_theme)
+        .transpose()?;
+    #[cfg(feature = "highlight")]
+    if let Some(theme) = highlight_theme {
+        zip.start_file(style::HIGHLIGHT_STYLE_PATH, zip_file_options)?;
+        zip.write_all(crate::highlight::theme_css(theme)?.as_bytes())?;
+
+        manifest.push(ManifestItem {
+            id: "highlight-style".to_string(),
+            path: PathBuf::from(style::HIGHLIGHT_STYLE_PATH),
+            media_type: Cow::Borrowed(style::CSS_MEDIA),
+            properties: vec![],
+            fallback: None,
+            spine: false,
+        });
+        stylesheets.push(style::HIGHLIGHT_STYLE_PATH.to_string());
+    }
+
+    for css in &config.css {
+        stylesheets.push(check_stylesheet(css, book.extra_files)?);
+    }
+
+    let cover = config
+        .cover_image(cx.output)
+        .map(|path| find_source_file("cover image", path, book.extra_files))
+        .transpose()?;
+
+    if let Some(cover) = cover {
+        if !cover.content_type.starts_with("image/") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "cover image `{}` has media type {}, which is not an image",
+                    cover.dest_path.display(),
+                    cover.content_type
+                ),
+            ));
+        }
+    }
+
+    let mut required = stylesheets.iter().cloned().collect::<HashSet<_>>();
+    required.extend(cover.map(|cover| cover.dest_path.to_string_lossy().replace('\\', "/")));
+    let mut referenced = HashSet::new();
+    for node in book.tree.nested() {
+        if let NavHeading::Chapter(_, chapter) = &node.heading {
+            referenced.extend(xhtml::chapter_resources(chapter));
+        }
+    }
+    let resources = select_resources(book.extra_files, &required, referenced, config)?;
+
+    // Generated documents are at the root of the container, where a chapter or resource may already be
+    let mut used_paths = book
+        .tree
+        .nested()
+        .filter_map(|node| match &node.heading {
+            NavHeading::Chapter(_, chapter) => Some(chapter_href(chapter)),
+            _ => None,
+        })
+        .chain(
+            resources
+                .iter()
+                .map(|file| file.dest_path.to_string_lossy().replace('\\', "/")),
+        )
+        .collect::<HashSet<_>>();
+    let cover_document = cover
+        .filter(|_| config.cover_page)
+        .map(|cover| (cover, unique_path(COVER_DOCUMENT, &mut used_paths)));
+    let notes_document = unique_path(xhtml::NOTES_DOCUMENT, &mut used_paths);
+    let nav_document = unique_path(NAV_DOCUMENT, &mut used_paths);
+
+    if let Some((cover, path)) = &cover_document {
+        zip.start_file(path, zip_file_options)?;
+        let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
+        write_cover_document(
+            &info.title,
+            &cover.dest_path.to_string_lossy().replace('\\', "/"),
+            &stylesheets,
+            &info.lang,
+            &mut writer,
+        )
+        .map_err(xhtml::xml_to_io_error)?;
+
+        manifest.push(ManifestItem {
+            id: "cover".to_string(),
+            path: PathBuf::from(path),
+            media_type: Cow::Borrowed(xhtml::XHTML_MEDIA),
+            properties: vec![],
+            fallback: None,
+            spine: true,
+        });
+    }
+
+    let chapter_links = xhtml::ChapterLinks::new(
+        book.tree.nested().filter_map(|node| match &node.heading {
+            NavHeading::Chapter(_, chapter) => Some(chapter),
+            _ => None,
+        }),
+        config.omitted_chapter_links.clone(),
+    );
+
+    let mut parts = HashSet::new();
+    part_chapters(&book.tree, &mut parts);
+
+    let spine_start = manifest.len();
+    let mut front_matter_end = None;
+    let mut body_matter = None;
+    let mut page_list = Vec::new();
 
     for item in book.tree.nested() {
         match &item.heading {
@@ -105,18 +702,57 @@ pub fn write_epub<W: std::io::Write + std::io::Seek>(
                     path
                 };
 
+                if chapter.numbered && front_matter_end.is_none() {
+                    front_matter_end = Some(manifest.len());
+                    body_matter = Some(chapter_href(chapter));
+                }
+                if config.nav.page_list {
+                    let href = chapter_href(chapter);
+                    page_list.extend(
+                        page_breaks(chapter)
+                            .into_iter()
+                            .map(|(id, label)| (format!("{href}#{id}"), label)),
+                    );
+                }
+
                 let str = in_file_path.to_string_lossy();
 
                 zip.start_file(str, zip_file_options.clone())?;
 
+                // With end-of-book footnotes, the content of the footnotes is in the notes document instead
+                let properties = content_properties(chapter.content.iter().filter(|elem| {
+                    config.footnotes != FootnotePlacement::EndOfBook
+                        || !matches!(elem, RichText::FootnoteDefinition(_))
+                }));
+
+                let section = match (chapter.numbered, front_matter_end) {
+                    (true, _) if parts.contains(&*chapter.dest_path) => SectionType::Part,
+                    (true, _) => SectionType::Chapter,
+                    (false, None) => SectionType::FrontMatter,
+                    (false, Some(_)) => SectionType::BackMatter,
+                };
+
                 let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
-                xhtml::write_chapter(chapter, &mut writer).map_err(xhtml::xml_to_io_error)?;
+                let mut cx = DocumentContext::for_chapter(chapter, config.footnotes)
+                    .with_stylesheets(&stylesheets)
+                    .with_lang(chapter.lang.as_deref().unwrap_or(&info.lang))
+                    .with_links(&chapter_links)
+                    .with_notes_document(&notes_document);
+                #[cfg(feature = "highlight")]
+                {
+                    cx.highlight = highlight_theme.is_some();
+                }
+                xhtml::write_chapter(chapter, title, section, &mut cx, &mut writer)
+                    .map_err(xhtml::xml_to_io_error)?;
+                if config.footnotes == FootnotePlacement::EndOfBook {
+                    notes.push((title.to_string(), cx));
+                }
 
                 let spine_item = ManifestItem {
-                    id: name_to_id(title),
+                    id: unique_id(&chapter.id, &mut used_ids),
                     path: in_file_path,
                     media_type: Cow::Borrowed(xhtml::XHTML_MEDIA),
-                    properties: vec![],
+                    properties,
                     fallback: None,
                     spine: true,
                 };
@@ -127,75 +763,124 @@ pub fn write_epub<W: std::io::Write + std::io::Seek>(
         }
     }
 
+    if notes.iter().any(|(_, cx)| !cx.notes.is_empty()) {
+        zip.start_file(&notes_document, zip_file_options)?;
+        let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
+        write_notes_document(
+            &notes_document,
+            &mut notes,
+            &stylesheets,
+            &info.lang,
+            &mut writer,
+        )
+        .map_err(xhtml::xml_to_io_error)?;
+
+        let properties = content_properties(
+            notes
+                .iter()
+                .flat_map(|(_, cx)| cx.notes.iter())
+                .flat_map(|note| &note.content),
+        );
+
+        manifest.push(ManifestItem {
+            id: "notes".to_string(),
+            path: PathBuf::from(&notes_document),
+            media_type: Cow::Borrowed(xhtml::XHTML_MEDIA),
+            properties,
+            fallback: None,
+            spine: true,
+        });
+    }
+
+    // Without numbered chapters, nothing is front matter, and the body matter starts at the first chapter
+    let front_matter_end = front_matter_end.unwrap_or(spine_start);
+    let body_matter = body_matter.or_else(|| {
+        book.tree.nested().find_map(|node| match &node.heading {
+            NavHeading::Chapter(_, chapter) => Some(chapter_href(chapter)),
+            _ => None,
+        })
+    });
+
+    let mut landmarks = Vec::new();
+    if config.nav.landmarks {
+        if let Some((_, path)) = &cover_document {
+            landmarks.push(("cover", path.clone(), "Cover"));
+        }
+        landmarks.push((
+            "toc",
+            format!("{nav_document}#{NAV_TOC_ID}"),
+            &*config.nav.title,
+        ));
+        if let Some(href) = body_matter {
+            landmarks.push(("bodymatter", href, "Start of Content"));
+        }
+    }
+
     let nav_item = ManifestItem {
-        id: format!("nav-toc"),
-        path: PathBuf::from("nav.xhtml"),
+        id: "nav-toc".to_string(),
+        path: PathBuf::from(&nav_document),
         media_type: Cow::Borrowed(xhtml::XHTML_MEDIA),
         properties: vec![ItemProperty::Nav],
         fallback: None,
-        spine: false, // for now, make this a config var later
+        spine: config.nav.spine != NavPlacement::None,
     };
 
-    manifest.push(nav_item);
-
-    zip.start_file("nav.xhtml", zip_file_options)?;
+    match config.nav.spine {
+        NavPlacement::Start => manifest.insert(spine_start, nav_item),
+        NavPlacement::AfterFrontMatter => manifest.insert(front_matter_end, nav_item),
+        _ => manifest.push(nav_item),
+    }
 
+    zip.start_file(&nav_document, zip_file_options)?;
     let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
-
-    writer
-        .write(XmlEvent::StartDocument {
-            version: xml::common::XmlVersion::Version10,
-            encoding: Some("UTF-8"),
-            standalone: None,
-        })
-        .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(
-            XmlEvent::start_element("html")
-                .ns(NS_NO_PREFIX, xhtml::NS_XHTML_URI)
-                .ns(NS_EPUB_PREFIX, NS_EPUB_URI),
+    write_nav_document(
+        &book.tree,
+        &config.nav,
+        &landmarks,
+        &page_list,
+        &stylesheets,
+        &info.lang,
+        &mut writer,
+    )
+    .map_err(xhtml::xml_to_io_error)?;
+
+    if config.ncx {
+        zip.start_file(ncx::NCX_DOCUMENT, zip_file_options)?;
+        let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
+        ncx::write_ncx(
+            &book.tree,
+            &info.title,
+            &info.ident.to_string(),
+            &mut writer,
         )
         .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::start_element("head"))
-        .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::start_element("title"))
-        .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::characters("Table of Contents"))
-        .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::end_element())
-        .map_err(xhtml::xml_to_io_error)?; // </title>
-    writer
-        .write(XmlEvent::end_element())
-        .map_err(xhtml::xml_to_io_error)?; // </head>
-    writer
-        .write(XmlEvent::start_element("body"))
-        .map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::start_element("nav").attr(Name::prefixed("type", NS_EPUB_PREFIX), "toc"))
-        .map_err(xhtml::xml_to_io_error)?;
-    write_nav(&book.tree, &mut writer).map_err(xhtml::xml_to_io_error)?;
-    writer
-        .write(XmlEvent::end_element())
-        .map_err(xhtml::xml_to_io_error)?; // </nav>
-    writer
-        .write(XmlEvent::end_element())
-        .map_err(xhtml::xml_to_io_error)?; // </body>
-    writer
-        .write(XmlEvent::end_element())
-        .map_err(xhtml::xml_to_io_error)?; // </html>
 
-    for file in book.extra_files {
-        let mut id = format!("non-md-res{}", manifest.len());
+        manifest.push(ManifestItem {
+            id: ncx::NCX_ID.to_string(),
+            path: PathBuf::from(ncx::NCX_DOCUMENT),
+            media_type: Cow::Borrowed(ncx::NCX_MEDIA),
+            properties: vec![],
+            fallback: None,
+            spine: false,
+        });
+    }
+
+    for file in resources {
+        let is_cover = cover.is_some_and(|cover| core::ptr::eq(cover, file));
 
         manifest.push(ManifestItem {
-            id,
+            id: if is_cover {
+                "cover-image".to_string()
+            } else {
+                unique_id(&format!("non-md-res{}", manifest.len()), &mut used_ids)
+            },
             path: file.dest_path.clone(),
             media_type: file.content_type.clone(),
-            properties: vec![],
+            properties: if is_cover {
+                vec![ItemProperty::CoverImage]
+            } else {
+                vec![]
+            },
             fallback: None,
             spine: false,
         });
@@ -207,8 +892,7 @@ pub fn write_epub<W: std::io::Write + std::io::Seek>(
 
     let package = package::EpubPackage { info, manifest };
 
-    let mut package_file = package_id;
-    package_file += ".opf";
+    let package_file = format!("{}.opf", book.id);
     zip.start_file(&package_file, zip_file_options)?;
 
     let mut writer = EventWriter::new_with_config(&mut zip, xml_config.clone());
@@ -264,6 +948,13 @@ pub fn write_epub<W: std::io::Write + std::io::Seek>(
         .write(XmlEvent::end_element())
         .map_err(xhtml::xml_to_io_error)?; // </container>
 
+    #[cfg(feature = "epub-signatures")]
+    if let Some(key) = &signing_key {
+        let digests = zip.take_digests();
+        zip.start_file(signature::SIGNATURES_FILE, zip_file_options)?;
+        signature::write_signatures(&digests, key, &mut zip)?;
+    }
+
     zip.finish()?;
     Ok(())
 }
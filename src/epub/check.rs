@@ -0,0 +1,810 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Read, Seek},
+    path::Path,
+};
+
+use xml::{
+    attribute::OwnedAttribute,
+    common::XmlVersion,
+    reader::{ParserConfig, XmlEvent},
+};
+use zip::{result::ZipError, CompressionMethod, ZipArchive};
+
+use super::{
+    config::CheckMode,
+    ncx::NCX_MEDIA,
+    package::{EPUB_PACKAGE_MEDIA_TYPE, NS_DC_URI, NS_OPF_URI},
+    style,
+};
+use crate::{
+    helpers,
+    xhtml::{NS_EPUB_URI, XHTML_MEDIA},
+};
+
+const EPUB_MEDIA: &str = "application/epub+zip";
+
+const CONTAINER_FILE: &str = "META-INF/container.xml";
+
+const NS_XLINK_URI: &str = "http://www.w3.org/1999/xlink";
+
+const SVG_MEDIA: &str = "image/svg+xml";
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Reading systems are likely to display the book, but something is missing or suspicious
+    Warning,
+    /// The epub does not conform to the specification
+    Error,
+}
+
+impl core::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// A conformance problem found in an epub file
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// The file in the container that has the problem, if it is specific to one
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl core::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: ", self.severity))?;
+        if let Some(file) = &self.file {
+            f.write_fmt(format_args!("{file}: "))?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Every problem found by [`check_epub`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport(pub Vec<Problem>);
+
+impl CheckReport {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.0
+            .iter()
+            .any(|problem| problem.severity == Severity::Error)
+    }
+
+    fn push(&mut self, severity: Severity, file: Option<&str>, message: String) {
+        self.0.push(Problem {
+            severity,
+            file: file.map(str::to_string),
+            message,
+        });
+    }
+
+    fn error(&mut self, file: Option<&str>, message: String) {
+        self.push(Severity::Error, file, message)
+    }
+
+    fn warning(&mut self, file: Option<&str>, message: String) {
+        self.push(Severity::Warning, file, message)
+    }
+}
+
+impl core::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in &self.0 {
+            f.write_fmt(format_args!("{problem}\n"))?;
+        }
+        let errors = self
+            .0
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .count();
+        let warnings = self.0.len() - errors;
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        f.write_fmt(format_args!(
+            "{errors} error{} and {warnings} warning{}",
+            plural(errors),
+            plural(warnings)
+        ))
+    }
+}
+
+/// An item of the package manifest
+struct ManifestEntry {
+    id: String,
+    href: String,
+    /// The path of the item in the container, or `None` for remote resources
+    path: Option<String>,
+    media_type: String,
+    properties: Vec<String>,
+    fallback: Option<String>,
+}
+
+/// The parts of the package document that are checked
+#[derive(Default)]
+struct PackageDocument {
+    unique_identifier: Option<String>,
+    identifiers: Vec<Option<String>>,
+    has_title: bool,
+    has_language: bool,
+    has_modified: bool,
+    ids: Vec<String>,
+    manifest: Vec<ManifestEntry>,
+    spine: Vec<String>,
+    /// The `toc` attribute of the spine, which refers to the NCX
+    spine_toc: Option<String>,
+}
+
+/// The ids and outgoing references of an xhtml document
+#[derive(Default)]
+struct ContentDocument {
+    ids: HashSet<String>,
+    /// The references of the document, and whether each is a hyperlink (rather than an embedded resource)
+    references: Vec<(String, bool)>,
+    has_toc_nav: bool,
+    /// Whether the `<head>` has a non-empty `<title>`
+    has_title: bool,
+}
+
+fn attr<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|a| a.name.namespace.is_none() && a.name.local_name == name)
+        .map(|a| &*a.value)
+}
+
+/// Whether `href` starts with a url scheme (such as `https:` or `mailto:`), and so does not refer to a file in the container
+fn has_scheme(href: &str) -> bool {
+    match href.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        None => false,
+    }
+}
+
+/// Resolves the path part of `href` against the file `base`, giving a path in the container.
+///
+/// Returns `None` if the path leaves the container
+fn resolve_path(base: &str, href: &str) -> Option<String> {
+    let path = href.split(['#', '?']).next().unwrap_or(href);
+    if path.is_empty() {
+        return Some(base.to_string());
+    }
+
+    let mut segments: Vec<&str> = match base.rsplit_once('/') {
+        Some((dir, _)) if !path.starts_with('/') => dir.split('/').collect(),
+        _ => Vec::new(),
+    };
+    let path = helpers::percent_decode(path);
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+fn is_valid_media_type(media_type: &str) -> bool {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.chars().all(|c| {
+                c.is_ascii_alphanumeric()
+                    || matches!(c, '!' | '#' | '$' | '&' | '^' | '_' | '.' | '+' | '-')
+            })
+    };
+    let essence = media_type.split(';').next().unwrap_or(media_type).trim();
+    essence
+        .split_once('/')
+        .is_some_and(|(ty, subty)| is_token(ty) && is_token(subty))
+}
+
+fn read_file<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str) -> io::Result<Option<Vec<u8>>> {
+    match zip.by_name(name) {
+        Ok(mut file) => {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            Ok(Some(content))
+        }
+        Err(ZipError::FileNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parser_config() -> ParserConfig {
+    ParserConfig::new()
+        .trim_whitespace(true)
+        .ignore_comments(true)
+}
+
+fn check_mimetype<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    report: &mut CheckReport,
+) -> io::Result<()> {
+    let mut first = match zip.by_index(0) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => {
+            report.error(None, "the container is empty".to_string());
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if first.name() != "mimetype" {
+        report.error(
+            None,
+            format!(
+                "the first file in the container must be `mimetype`, but is `{}`",
+                first.name()
+            ),
+        );
+        return Ok(());
+    }
+    if first.compression() != CompressionMethod::Stored {
+        report.error(
+            Some("mimetype"),
+            "must be stored without compression".to_string(),
+        );
+    }
+    let mut content = String::new();
+    first.read_to_string(&mut content)?;
+    if content != EPUB_MEDIA {
+        report.error(
+            Some("mimetype"),
+            format!("must contain exactly `{EPUB_MEDIA}`"),
+        );
+    }
+    Ok(())
+}
+
+/// Finds the path of the package document in `META-INF/container.xml`
+fn find_package<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    report: &mut CheckReport,
+) -> io::Result<Option<String>> {
+    let Some(container) = read_file(zip, CONTAINER_FILE)? else {
+        report.error(None, format!("`{CONTAINER_FILE}` is missing"));
+        return Ok(None);
+    };
+
+    for event in parser_config().create_reader(&*container) {
+        match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) if name.local_name == "rootfile" => {
+                if attr(&attributes, "media-type") == Some(EPUB_PACKAGE_MEDIA_TYPE) {
+                    if let Some(path) = attr(&attributes, "full-path") {
+                        return Ok(Some(path.to_string()));
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                report.error(Some(CONTAINER_FILE), format!("is not well-formed: {e}"));
+                return Ok(None);
+            }
+        }
+    }
+
+    report.error(
+        Some(CONTAINER_FILE),
+        "does not name a package document".to_string(),
+    );
+    Ok(None)
+}
+
+fn parse_package(
+    content: &[u8],
+    package_path: &str,
+    report: &mut CheckReport,
+) -> Option<PackageDocument> {
+    let mut package = PackageDocument::default();
+    let mut stack: Vec<String> = Vec::new();
+    let mut text = String::new();
+
+    for event in parser_config().create_reader(content) {
+        match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => {
+                if let Some(id) = attr(&attributes, "id") {
+                    package.ids.push(id.to_string());
+                }
+                let parent = stack.last().map(String::as_str);
+                match (parent, name.namespace.as_deref(), &*name.local_name) {
+                    (None, Some(NS_OPF_URI), "package") => {
+                        package.unique_identifier =
+                            attr(&attributes, "unique-identifier").map(str::to_string);
+                    }
+                    (Some("metadata"), Some(NS_DC_URI), "identifier") => {
+                        package
+                            .identifiers
+                            .push(attr(&attributes, "id").map(str::to_string));
+                    }
+                    (Some("metadata"), Some(NS_OPF_URI), "meta")
+                        if attr(&attributes, "property") == Some("dcterms:modified") =>
+                    {
+                        package.has_modified = true;
+                    }
+                    (Some("manifest"), Some(NS_OPF_URI), "item") => {
+                        let href = attr(&attributes, "href").unwrap_or_default().to_string();
+                        let path = if has_scheme(&href) {
+                            None
+                        } else {
+                            Some(resolve_path(package_path, &href).unwrap_or_default())
+                        };
+                        package.manifest.push(ManifestEntry {
+                            id: attr(&attributes, "id").unwrap_or_default().to_string(),
+                            href,
+                            path,
+                            media_type: attr(&attributes, "media-type")
+                                .unwrap_or_default()
+                                .to_string(),
+                            properties: attr(&attributes, "properties")
+                                .unwrap_or_default()
+                                .split_whitespace()
+                                .map(str::to_string)
+                                .collect(),
+                            fallback: attr(&attributes, "fallback").map(str::to_string),
+                        });
+                    }
+                    (Some("package"), Some(NS_OPF_URI), "spine") => {
+                        package.spine_toc = attr(&attributes, "toc").map(str::to_string);
+                    }
+                    (Some("spine"), Some(NS_OPF_URI), "itemref") => {
+                        package
+                            .spine
+                            .push(attr(&attributes, "idref").unwrap_or_default().to_string());
+                    }
+                    _ => {}
+                }
+                text.clear();
+                stack.push(name.local_name);
+            }
+            Ok(XmlEvent::Characters(chars)) => text.push_str(&chars),
+            Ok(XmlEvent::EndElement { name }) => {
+                stack.pop();
+                if stack.last().map(String::as_str) == Some("metadata")
+                    && name.namespace.as_deref() == Some(NS_DC_URI)
+                    && !text.trim().is_empty()
+                {
+                    match &*name.local_name {
+                        "title" => package.has_title = true,
+                        "language" => package.has_language = true,
+                        _ => {}
+                    }
+                }
+                text.clear();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                report.error(Some(package_path), format!("is not well-formed: {e}"));
+                return None;
+            }
+        }
+    }
+
+    Some(package)
+}
+
+fn check_package(
+    package: &PackageDocument,
+    package_path: &str,
+    files: &HashSet<&str>,
+    report: &mut CheckReport,
+) {
+    let file = Some(package_path);
+
+    let mut seen = HashSet::new();
+    for id in &package.ids {
+        if !seen.insert(id) {
+            report.error(file, format!("the id `{id}` is used more than once"));
+        }
+    }
+
+    match &package.unique_identifier {
+        Some(unique) if package.identifiers.iter().flatten().any(|id| id == unique) => {}
+        Some(unique) => report.error(
+            file,
+            format!("the unique identifier `{unique}` is not the id of a `dc:identifier`"),
+        ),
+        None => report.error(
+            file,
+            "the package has no `unique-identifier` attribute".to_string(),
+        ),
+    }
+    if package.identifiers.is_empty() {
+        report.error(file, "the metadata has no `dc:identifier`".to_string());
+    }
+    if !package.has_title {
+        report.error(file, "the metadata has no `dc:title`".to_string());
+    }
+    if !package.has_language {
+        report.error(file, "the metadata has no `dc:language`".to_string());
+    }
+    if !package.has_modified {
+        report.error(
+            file,
+            "the metadata has no `dcterms:modified` date".to_string(),
+        );
+    }
+
+    let mut ids = HashSet::new();
+    let mut paths = HashSet::new();
+    for item in &package.manifest {
+        if !ids.insert(&item.id) {
+            report.error(
+                file,
+                format!(
+                    "the id `{}` is used by more than one manifest item",
+                    item.id
+                ),
+            );
+        }
+        if !is_valid_media_type(&item.media_type) {
+            report.error(
+                file,
+                format!(
+                    "manifest item `{}` has the invalid media type `{}`",
+                    item.id, item.media_type
+                ),
+            );
+        } else if item.media_type == "application/octet-stream" {
+            report.warning(
+                file,
+                format!(
+                    "manifest item `{}` (`{}`) has no specific media type",
+                    item.id, item.href
+                ),
+            );
+        }
+        if let Some(fallback) = &item.fallback {
+            if !package.manifest.iter().any(|other| &other.id == fallback) {
+                report.error(
+                    file,
+                    format!(
+                        "the fallback of manifest item `{}` is `{fallback}`, which is not in the manifest",
+                        item.id
+                    ),
+                );
+            }
+        }
+        let Some(path) = &item.path else {
+            continue;
+        };
+        if !paths.insert(path) {
+            report.error(
+                file,
+                format!("`{}` is in the manifest more than once", item.href),
+            );
+        }
+        if !files.contains(&**path) {
+            report.error(
+                file,
+                format!(
+                    "manifest item `{}` refers to `{}`, which is not in the container",
+                    item.id, item.href
+                ),
+            );
+        }
+    }
+
+    if package.spine.is_empty() {
+        report.error(file, "the spine is empty".to_string());
+    }
+    for idref in &package.spine {
+        match package.manifest.iter().find(|item| &item.id == idref) {
+            None => report.error(
+                file,
+                format!("the spine refers to `{idref}`, which is not in the manifest"),
+            ),
+            Some(item)
+                if item.media_type != XHTML_MEDIA
+                    && item.media_type != SVG_MEDIA
+                    && item.fallback.is_none() =>
+            {
+                report.error(
+                    file,
+                    format!(
+                        "spine item `{idref}` has the media type `{}`, which is not a content document",
+                        item.media_type
+                    ),
+                )
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(toc) = &package.spine_toc {
+        match package.manifest.iter().find(|item| &item.id == toc) {
+            Some(item) if item.media_type == NCX_MEDIA => {}
+            Some(item) => report.error(
+                file,
+                format!(
+                    "the `toc` of the spine is `{toc}`, which has the media type `{}`, instead of `{NCX_MEDIA}`",
+                    item.media_type
+                ),
+            ),
+            None => report.error(
+                file,
+                format!("the `toc` of the spine is `{toc}`, which is not in the manifest"),
+            ),
+        }
+    }
+
+    let navs = package
+        .manifest
+        .iter()
+        .filter(|item| item.properties.iter().any(|prop| prop == "nav"))
+        .collect::<Vec<_>>();
+    match &*navs {
+        [] => report.error(file, "the manifest has no navigation document".to_string()),
+        [nav] if nav.media_type != XHTML_MEDIA => report.error(
+            file,
+            format!(
+                "the navigation document `{}` has the media type `{}`, instead of `{XHTML_MEDIA}`",
+                nav.href, nav.media_type
+            ),
+        ),
+        [_] => {}
+        _ => report.error(
+            file,
+            "the manifest has more than one navigation document".to_string(),
+        ),
+    }
+}
+
+fn parse_content_document(
+    content: &[u8],
+    path: &str,
+    report: &mut CheckReport,
+) -> Option<ContentDocument> {
+    let mut document = ContentDocument::default();
+    let mut in_title = false;
+
+    for event in parser_config().create_reader(content) {
+        match event {
+            Ok(XmlEvent::StartDocument { version, .. }) if version != XmlVersion::Version10 => {
+                report.error(
+                    Some(path),
+                    format!("is XML {version}, but content documents must be XML 1.0"),
+                );
+            }
+            Ok(XmlEvent::EndElement { name }) if name.local_name == "title" => in_title = false,
+            Ok(XmlEvent::Characters(text)) if in_title && !text.trim().is_empty() => {
+                document.has_title = true;
+            }
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => {
+                in_title = name.local_name == "title";
+                for attribute in &attributes {
+                    let namespace = attribute.name.namespace.as_deref();
+                    let value = attribute.value.to_string();
+                    match (namespace, &*attribute.name.local_name) {
+                        (None, "id") | (Some(xml::namespace::NS_XML_URI), "id") => {
+                            document.ids.insert(value);
+                        }
+                        (None, "href") => {
+                            let hyperlink = matches!(&*name.local_name, "a" | "area");
+                            document.references.push((value, hyperlink));
+                        }
+                        (None, "src" | "poster" | "data") | (Some(NS_XLINK_URI), "href") => {
+                            document.references.push((value, false));
+                        }
+                        (Some(NS_EPUB_URI), "type")
+                            if name.local_name == "nav"
+                                && attribute.value.split_whitespace().any(|ty| ty == "toc") =>
+                        {
+                            document.has_toc_nav = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                report.error(Some(path), format!("is not well-formed: {e}"));
+                return None;
+            }
+        }
+    }
+
+    Some(document)
+}
+
+/// Checks the epub file read from `reader` for conformance problems.
+///
+/// This checks the structure of the container, the required metadata of the package document, the manifest and spine,
+/// and that every reference in the content documents and stylesheets can be resolved within the container.
+/// `Err` is only returned if the file cannot be read as a zip file.
+pub fn check_epub<R: Read + Seek>(reader: R) -> io::Result<CheckReport> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut report = CheckReport::default();
+
+    check_mimetype(&mut zip, &mut report)?;
+
+    let Some(package_path) = find_package(&mut zip, &mut report)? else {
+        return Ok(report);
+    };
+    let Some(package_content) = read_file(&mut zip, &package_path)? else {
+        report.error(
+            Some(CONTAINER_FILE),
+            format!("the package document `{package_path}` is not in the container"),
+        );
+        return Ok(report);
+    };
+    let Some(package) = parse_package(&package_content, &package_path, &mut report) else {
+        return Ok(report);
+    };
+
+    let names = zip.file_names().map(str::to_string).collect::<Vec<_>>();
+    let files = names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !name.ends_with('/'))
+        .collect::<HashSet<_>>();
+
+    check_package(&package, &package_path, &files, &mut report);
+
+    let manifested = package
+        .manifest
+        .iter()
+        .filter_map(|item| item.path.as_deref())
+        .collect::<HashSet<_>>();
+    let mut unlisted = files
+        .iter()
+        .filter(|name| {
+            **name != "mimetype"
+                && !name.starts_with("META-INF/")
+                && **name != package_path
+                && !manifested.contains(*name)
+        })
+        .collect::<Vec<_>>();
+    unlisted.sort();
+    for name in unlisted {
+        report.warning(Some(name), "is not listed in the manifest".to_string());
+    }
+
+    let spine = package
+        .spine
+        .iter()
+        .filter_map(|idref| package.manifest.iter().find(|item| &item.id == idref))
+        .filter_map(|item| item.path.as_deref())
+        .collect::<HashSet<_>>();
+
+    let mut documents = HashMap::new();
+    for item in &package.manifest {
+        let Some(path) = item.path.as_deref().filter(|path| files.contains(path)) else {
+            continue;
+        };
+        let content = read_file(&mut zip, path)?.unwrap_or_default();
+        match &*item.media_type {
+            XHTML_MEDIA => {
+                if let Some(document) = parse_content_document(&content, path, &mut report) {
+                    if item.properties.iter().any(|prop| prop == "nav") && !document.has_toc_nav {
+                        report.error(
+                            Some(path),
+                            "the navigation document has no `toc` nav".to_string(),
+                        );
+                    }
+                    if !document.has_title {
+                        report.warning(Some(path), "has no `<title>`".to_string());
+                    }
+                    documents.insert(path, document);
+                }
+            }
+            style::CSS_MEDIA => {
+                let css = String::from_utf8_lossy(&content);
+                for url in style::font_face_urls(&css) {
+                    match resolve_path(path, &url) {
+                        Some(font) if files.contains(&*font) => {}
+                        _ => report.error(
+                            Some(path),
+                            format!("the font `{url}` is not in the container"),
+                        ),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut paths = documents.keys().copied().collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+        for (href, hyperlink) in &documents[path].references {
+            if has_scheme(href) {
+                continue;
+            }
+            let Some(target) = resolve_path(path, href) else {
+                report.error(
+                    Some(path),
+                    format!("`{href}` refers to a file outside of the container"),
+                );
+                continue;
+            };
+
+            if !files.contains(&*target) {
+                // Hyperlinks to missing documents are usually links to chapters left out of a part or chapter output
+                let message = if *hyperlink {
+                    format!("links to `{href}`, which is not included in this output")
+                } else {
+                    format!("refers to `{href}`, which is not in the container")
+                };
+                report.error(Some(path), message);
+                continue;
+            }
+
+            if *hyperlink
+                && target != path
+                && documents.contains_key(&*target)
+                && !spine.contains(&*target)
+            {
+                report.error(
+                    Some(path),
+                    format!("links to `{href}`, which is not in the spine"),
+                );
+            }
+
+            if let (Some((_, fragment)), Some(document)) =
+                (href.split_once('#'), documents.get(&*target))
+            {
+                let fragment = helpers::percent_decode(fragment);
+                if !fragment.is_empty() && !document.ids.contains(&fragment) {
+                    report.warning(
+                        Some(path),
+                        format!("links to `{href}`, but `{target}` has no element with that id"),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Checks the epub file at `path`, reporting any problems found according to `mode`.
+///
+/// Returns an error if the problems fail the build
+pub fn check_output(path: &Path, mode: CheckMode) -> io::Result<()> {
+    if mode == CheckMode::Off {
+        return Ok(());
+    }
+
+    let report = check_epub(File::open(path)?)?;
+    if report.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{report} in {}", path.display());
+
+    let fails = match mode {
+        CheckMode::Deny => report.has_errors(),
+        CheckMode::DenyWarnings => true,
+        _ => false,
+    };
+    if fails {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} failed the conformance check", path.display()),
+        ));
+    }
+    Ok(())
+}
@@ -1,25 +1,135 @@
 use std::{
     collections::{HashMap, HashSet},
+    io,
     ops::Deref,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde_derive::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use uuid::Uuid;
 
-use crate::config::{FileIds, SharedConfig};
+use crate::{
+    config::{FileIds, SharedConfig},
+    xhtml::{FootnotePlacement, OmittedChapterLinks},
+    Output,
+};
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize)]
 #[serde(rename_all = "kebab-case", default)]
 #[non_exhaustive]
 pub struct EpubConfig {
     #[serde(flatten)]
     pub shared: SharedConfig,
     pub file_ids: FileIds<PackageId>,
-    #[cfg(feature = "epub-signatures")]
+    pub footnotes: FootnotePlacement,
+    /// How links to chapters that are not included in an output are written
+    pub omitted_chapter_links: OmittedChapterLinks,
+    /// Stylesheets linked from every document, relative to the book source directory
+    pub css: Vec<PathBuf>,
+    /// Whether the bundled default stylesheet is included (before any stylesheets in `css`)
+    pub default_css: bool,
+    /// The cover image of every output, relative to the book source directory
+    pub cover: Option<PathBuf>,
+    /// Cover images for specific outputs, overriding `cover`
+    pub covers: FileIds<PathBuf>,
+    /// Whether a `cover.xhtml` document displaying the cover image is placed at the start of the spine
+    pub cover_page: bool,
+    pub metadata: EpubMetadata,
+    /// Metadata for specific outputs, overriding the fields of `metadata` that are set
+    pub output_metadata: FileIds<EpubMetadata>,
+    pub resources: ResourceConfig,
+    /// The modification time of the outputs, in RFC 3339 format. Setting this makes the build reproducible,
+    /// except for the signatures of outputs signed with an ECDSA `signing-key`
+    pub timestamp: Option<String>,
+    pub nav: NavConfig,
+    /// Whether an EPUB 2 table of contents (`toc.ncx`) is written, for reading systems that do not support the navigation document
+    pub ncx: bool,
+    /// What is done with the problems found when checking each output for conformance
+    pub check: CheckMode,
+    /// The keys each output is signed with. Setting this without the `epub-signatures` feature is an error, rather than writing unsigned outputs
     pub signing_key: Option<SignatureKeyPair>,
 }
 
+impl Default for EpubConfig {
+    fn default() -> Self {
+        Self {
+            shared: SharedConfig::default(),
+            file_ids: FileIds::default(),
+            footnotes: FootnotePlacement::default(),
+            omitted_chapter_links: OmittedChapterLinks::default(),
+            css: Vec::new(),
+            default_css: true,
+            cover: None,
+            covers: FileIds::default(),
+            cover_page: true,
+            metadata: EpubMetadata::default(),
+            output_metadata: FileIds::default(),
+            resources: ResourceConfig::default(),
+            timestamp: None,
+            nav: NavConfig::default(),
+            ncx: false,
+            check: CheckMode::default(),
+            signing_key: None,
+        }
+    }
+}
+
+impl EpubConfig {
+    /// The cover image of `output`, if it has one
+    pub fn cover_image(&self, output: &Output) -> Option<&Path> {
+        self.covers
+            .get(output)
+            .or(self.cover.as_ref())
+            .map(PathBuf::as_path)
+    }
+
+    /// The fixed modification time of reproducible builds.
+    ///
+    /// This is `timestamp` if it is set, and otherwise the time given by the `SOURCE_DATE_EPOCH` environment variable.
+    /// If neither is set, the build is not reproducible, and `None` is returned.
+    pub fn reproducible_time(&self) -> io::Result<Option<OffsetDateTime>> {
+        if let Some(timestamp) = &self.timestamp {
+            return OffsetDateTime::parse(timestamp, &Rfc3339)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => {
+                let epoch = epoch
+                    .trim()
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                OffsetDateTime::from_unix_timestamp(epoch)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Fails if the config sets options that need a feature this build does not have
+    pub fn check_features(&self) -> io::Result<()> {
+        #[cfg(not(feature = "epub-signatures"))]
+        if self.signing_key.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "`signing-key` is set, but mdbook-fiction-tools was built without the `epub-signatures` feature",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The publication metadata of `output`
+    pub fn metadata_for(&self, output: &Output) -> EpubMetadata {
+        match self.output_metadata.get(output) {
+            Some(over) => self.metadata.merge(over),
+            None => self.metadata.clone(),
+        }
+    }
+}
+
 impl Deref for EpubConfig {
     type Target = SharedConfig;
 
@@ -28,8 +138,164 @@ impl Deref for EpubConfig {
     }
 }
 
+/// Selects the files of the book source directory (other than chapters) that are packaged in each output.
+///
+/// Files referenced by the chapters of the output are always packaged, unless they are excluded. The stylesheets and the cover image are always packaged
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+#[non_exhaustive]
+pub struct ResourceConfig {
+    /// Globs matching files that are packaged in every output, even if nothing references them
+    pub include: Vec<String>,
+    /// Globs matching files that are not packaged, even if they are referenced or included. Does not apply to the stylesheets and the cover image
+    pub exclude: Vec<String>,
+}
+
+/// Configuration of the navigation document, `nav.xhtml`
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+#[non_exhaustive]
+pub struct NavConfig {
+    /// Where the navigation document is placed in the spine, if at all
+    pub spine: NavPlacement,
+    /// The title of the table of contents
+    pub title: String,
+    /// The number of levels of the table of contents. All levels are included if this is `None`
+    pub depth: Option<usize>,
+    /// Whether a `landmarks` nav is written, linking to the cover, the table of contents, and the start of the body matter
+    pub landmarks: bool,
+    /// Whether a `page-list` nav is written, linking to every page break marker (`epub:type="pagebreak"`) in the book
+    pub page_list: bool,
+}
+
+impl Default for NavConfig {
+    fn default() -> Self {
+        Self {
+            spine: NavPlacement::default(),
+            title: "Table of Contents".to_string(),
+            depth: None,
+            landmarks: true,
+            page_list: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum NavPlacement {
+    /// Not in the spine. Reading systems still display the table of contents in their own interface
+    #[default]
+    None,
+    /// Before the first chapter (but after the cover page)
+    Start,
+    /// After the unnumbered chapters before the first numbered chapter in `SUMMARY.md`
+    AfterFrontMatter,
+    /// After every other document
+    End,
+}
+
+/// What is done with the problems found by the conformance checker (see [`check_epub`][super::check::check_epub])
+#[derive(Deserialize, Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum CheckMode {
+    /// The outputs are not checked
+    Off,
+    /// Problems are reported, but do not fail the build
+    #[default]
+    Warn,
+    /// Errors fail the build, and warnings are only reported
+    Deny,
+    /// Both errors and warnings fail the build
+    DenyWarnings,
+}
+
+/// Publication metadata written to the package document, in addition to the title, language, and authors of the book
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+#[non_exhaustive]
+pub struct EpubMetadata {
+    pub subtitle: Option<String>,
+    /// The title used when sorting the publication
+    pub title_file_as: Option<String>,
+    pub publisher: Option<String>,
+    /// The publication date, in the W3C date format (`YYYY`, `YYYY-MM`, or `YYYY-MM-DD`, optionally followed by a time)
+    pub date: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Option<Vec<String>>,
+    pub rights: Option<String>,
+    pub contributors: Option<Vec<Contributor>>,
+    /// The names used when sorting the authors and contributors, such as `"Horman, Connor"`, keyed by their name
+    pub file_as: Option<HashMap<String, String>>,
+}
+
+impl EpubMetadata {
+    /// Combines `self` with `over`, using the fields of `over` that are set
+    pub fn merge(&self, over: &Self) -> Self {
+        Self {
+            subtitle: over.subtitle.clone().or_else(|| self.subtitle.clone()),
+            title_file_as: over
+                .title_file_as
+                .clone()
+                .or_else(|| self.title_file_as.clone()),
+            publisher: over.publisher.clone().or_else(|| self.publisher.clone()),
+            date: over.date.clone().or_else(|| self.date.clone()),
+            description: over
+                .description
+                .clone()
+                .or_else(|| self.description.clone()),
+            subjects: over.subjects.clone().or_else(|| self.subjects.clone()),
+            rights: over.rights.clone().or_else(|| self.rights.clone()),
+            contributors: over
+                .contributors
+                .clone()
+                .or_else(|| self.contributors.clone()),
+            file_as: over.file_as.clone().or_else(|| self.file_as.clone()),
+        }
+    }
+
+    /// The sort name of the author or contributor `name`, if one is specified
+    pub fn file_as(&self, name: &str) -> Option<&str> {
+        self.file_as.as_ref()?.get(name).map(String::as_str)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Contributor {
+    pub name: String,
+    pub role: Option<ContributorRole>,
+}
+
+/// The role of a contributor to the book, written as a MARC relator code
+#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum ContributorRole {
+    Editor,
+    Illustrator,
+    Translator,
+    /// Any other role, given by its MARC relator code (<https://id.loc.gov/vocabulary/relators.html>)
+    #[serde(untagged)]
+    Relator(String),
+}
+
+impl ContributorRole {
+    pub fn relator_code(&self) -> &str {
+        match self {
+            Self::Editor => "edt",
+            Self::Illustrator => "ill",
+            Self::Translator => "trl",
+            Self::Relator(code) => code,
+        }
+    }
+}
+
+/// The keys used to sign each output. The paths are relative to the root directory of the book
 #[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct SignatureKeyPair {
+    /// The private key, in PKCS#8 format (or PKCS#1 for RSA keys), either PEM or DER encoded
     pub private: PathBuf,
     #[serde(flatten)]
     pub public: SignaturePublicKey,
@@ -39,9 +305,17 @@ pub struct SignatureKeyPair {
 #[serde(untagged)]
 #[non_exhaustive]
 pub enum SignaturePublicKey {
-    Regular(PathBuf),
-    Pgp { pgp: PathBuf },
-    Certificate { cert: PathBuf },
+    /// A public key in SubjectPublicKeyInfo format, either PEM or DER encoded
+    Regular {
+        public: PathBuf,
+    },
+    Pgp {
+        pgp: PathBuf,
+    },
+    /// An X.509 certificate, either PEM or DER encoded, which is also included in the signature
+    Certificate {
+        cert: PathBuf,
+    },
 }
 
 #[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
@@ -53,6 +327,19 @@ pub enum PackageId {
     Isbn { isbn: String },
 }
 
+/// The namespace of the name-based UUIDs used as the default identifiers of reproducible builds
+const PACKAGE_ID_NAMESPACE: Uuid = Uuid::from_u128(0x90ee0548_2982_41ac_b29c_cbb155c5d719);
+
+impl PackageId {
+    /// A UUID derived from the title of the book and the output id, which is the same for every build of the output
+    pub fn derived(title: &str, output_id: &str) -> Self {
+        let name = format!("{title}\0{output_id}");
+        PackageId::Uuid {
+            uuid: Uuid::new_v5(&PACKAGE_ID_NAMESPACE, name.as_bytes()),
+        }
+    }
+}
+
 impl core::fmt::Display for PackageId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("urn:")?;
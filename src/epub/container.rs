@@ -0,0 +1,126 @@
+use std::{
+    fs::File,
+    io::{self, Seek, Write},
+    path::{Component, Path, PathBuf},
+};
+
+#[cfg(feature = "epub-signatures")]
+use ring::digest::{Context, Digest, SHA256};
+use zip::write::{FileOptions, ZipWriter};
+
+/// The directory that the exploded container of the output with `id` is written to, in the output directory `dest`.
+///
+/// Chapter output ids can be set by the id of a heading, so an id that is not a single file name (such as `..`) is rejected, rather than replacing a directory outside of `dest`
+pub fn exploded_dir(dest: &Path, id: &str) -> io::Result<PathBuf> {
+    let mut components = Path::new(id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == id => Ok(dest.join(id)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the output id `{id}` cannot be used as the name of a save-temps directory"),
+        )),
+    }
+}
+
+/// Writes the files of the OCF container to the epub zip file, and optionally also to a directory (the exploded container)
+pub struct ContainerWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    exploded_dir: Option<PathBuf>,
+    exploded_file: Option<File>,
+    /// The SHA-256 digest of each file written so far, when they are recorded for signing
+    #[cfg(feature = "epub-signatures")]
+    digests: Option<Vec<(String, Context)>>,
+}
+
+impl<W: Write + Seek> ContainerWriter<W> {
+    /// Creates a writer for the zip file `writer`.
+    ///
+    /// If `exploded_dir` is set, any existing directory at that path is replaced by the exploded container
+    pub fn new(writer: W, exploded_dir: Option<&Path>) -> io::Result<Self> {
+        if let Some(dir) = exploded_dir {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir)?;
+            }
+            std::fs::create_dir_all(dir)?;
+        }
+
+        Ok(Self {
+            zip: ZipWriter::new(writer),
+            exploded_dir: exploded_dir.map(Path::to_path_buf),
+            exploded_file: None,
+            #[cfg(feature = "epub-signatures")]
+            digests: None,
+        })
+    }
+
+    /// Records the digests of the files written from now on, which are returned by [`Self::take_digests`]
+    #[cfg(feature = "epub-signatures")]
+    pub fn record_digests(&mut self) {
+        self.digests = Some(Vec::new());
+    }
+
+    /// Returns the path and digest of every file written since [`Self::record_digests`] was called, and stops recording them
+    #[cfg(feature = "epub-signatures")]
+    pub fn take_digests(&mut self) -> Vec<(String, Digest)> {
+        self.digests
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, cx)| (name, cx.finish()))
+            .collect()
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        self.zip.set_comment(comment);
+    }
+
+    /// Starts the file at `name` (relative to the root of the container). Any subsequent writes go to this file
+    pub fn start_file<S: Into<String>>(&mut self, name: S, options: FileOptions) -> io::Result<()> {
+        let name = name.into();
+
+        if let Some(dir) = &self.exploded_dir {
+            let path = dir.join(&name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            self.exploded_file = Some(File::create(path)?);
+        }
+
+        #[cfg(feature = "epub-signatures")]
+        if let Some(digests) = &mut self.digests {
+            digests.push((name.clone(), Context::new(&SHA256)));
+        }
+
+        self.zip.start_file(name, options)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(file) = &mut self.exploded_file {
+            file.flush()?;
+        }
+        Ok(self.zip.finish()?)
+    }
+}
+
+impl<W: Write + Seek> Write for ContainerWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.zip.write(buf)?;
+        if let Some(file) = &mut self.exploded_file {
+            file.write_all(&buf[..len])?;
+        }
+        #[cfg(feature = "epub-signatures")]
+        if let Some((_, cx)) = self.digests.as_mut().and_then(|digests| digests.last_mut()) {
+            cx.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.zip.flush()?;
+        if let Some(file) = &mut self.exploded_file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use time::format_description::well_known::Rfc3339;
 use xml::{name::Name, writer::XmlEvent, EventWriter};
 
-use super::config::PackageId;
+use super::config::{EpubMetadata, PackageId};
 
 pub const NS_CONTAINER_URI: &str = "urn:oasis:names:tc:opendocument:xmlns:container";
 
@@ -15,6 +15,42 @@ pub struct EpubFileInfo {
     pub ident: PackageId,
     pub lang: String,
     pub creators: Vec<String>,
+    /// The time written as the `dcterms:modified` date, and used as the modification time of every file in the container
+    pub modified: time::OffsetDateTime,
+    pub metadata: EpubMetadata,
+    /// The title of the series the publication belongs to, and its position in the series
+    pub series: Option<(String, usize)>,
+}
+
+/// Writes a `<meta>` element refining the metadata element with the given `id`
+fn write_refinement<W: std::io::Write>(
+    w: &mut EventWriter<W>,
+    id: &str,
+    property: &str,
+    scheme: Option<&str>,
+    value: &str,
+) -> xml::writer::Result<()> {
+    let refines = format!("#{id}");
+    let mut start = XmlEvent::start_element("meta")
+        .attr("refines", &refines)
+        .attr("property", property);
+    if let Some(scheme) = scheme {
+        start = start.attr("scheme", scheme);
+    }
+    w.write(start)?;
+    w.write(XmlEvent::characters(value))?;
+    w.write(XmlEvent::end_element()) // </meta>
+}
+
+/// Writes a simple Dublin Core element, such as `dc:publisher`
+fn write_dc_element<W: std::io::Write>(
+    w: &mut EventWriter<W>,
+    name: &str,
+    value: &str,
+) -> xml::writer::Result<()> {
+    w.write(XmlEvent::start_element(Name::prefixed(name, NS_DC_PREFIX)))?;
+    w.write(XmlEvent::characters(value))?;
+    w.write(XmlEvent::end_element())
 }
 
 impl EpubFileInfo {
@@ -22,7 +58,9 @@ impl EpubFileInfo {
         &self,
         w: &mut EventWriter<W>,
     ) -> xml::writer::Result<()> {
-        let modified = time::OffsetDateTime::now_utc()
+        let modified = self
+            .modified
+            .to_offset(time::UtcOffset::UTC)
             .replace_nanosecond(0)
             .unwrap();
         w.write(
@@ -31,25 +69,99 @@ impl EpubFileInfo {
         )?;
         w.write(XmlEvent::characters(&self.ident.to_string()))?;
         w.write(XmlEvent::end_element())?; // </dc:identifier>
-        w.write(XmlEvent::start_element(Name::prefixed(
-            "title",
-            NS_DC_PREFIX,
-        )))?;
-        w.write(XmlEvent::characters(&self.title))?;
-        w.write(XmlEvent::end_element())?; // </dc:title>
-        w.write(XmlEvent::start_element(Name::prefixed(
-            "language",
-            NS_DC_PREFIX,
-        )))?;
-        w.write(XmlEvent::characters(&self.lang))?;
-        w.write(XmlEvent::end_element())?; // </dc:language>
-        for creator in &self.creators {
-            w.write(XmlEvent::start_element(Name::prefixed(
-                "creator",
-                NS_DC_PREFIX,
-            )))?;
-            w.write(XmlEvent::characters(&creator))?;
-            w.write(XmlEvent::end_element())? // </dc:creator>
+        let meta = &self.metadata;
+        let refine_title = meta.subtitle.is_some() || meta.title_file_as.is_some();
+        if refine_title {
+            w.write(
+                XmlEvent::start_element(Name::prefixed("title", NS_DC_PREFIX)).attr("id", "title"),
+            )?;
+            w.write(XmlEvent::characters(&self.title))?;
+            w.write(XmlEvent::end_element())?; // </dc:title>
+            write_refinement(w, "title", "title-type", None, "main")?;
+            if let Some(file_as) = &meta.title_file_as {
+                write_refinement(w, "title", "file-as", None, file_as)?;
+            }
+        } else {
+            write_dc_element(w, "title", &self.title)?;
+        }
+        if let Some(subtitle) = &meta.subtitle {
+            w.write(
+                XmlEvent::start_element(Name::prefixed("title", NS_DC_PREFIX))
+                    .attr("id", "subtitle"),
+            )?;
+            w.write(XmlEvent::characters(subtitle))?;
+            w.write(XmlEvent::end_element())?; // </dc:title>
+            write_refinement(w, "subtitle", "title-type", None, "subtitle")?;
+        }
+        write_dc_element(w, "language", &self.lang)?;
+        for (n, creator) in self.creators.iter().enumerate() {
+            let id = format!("creator-{}", n + 1);
+            w.write(
+                XmlEvent::start_element(Name::prefixed("creator", NS_DC_PREFIX)).attr("id", &id),
+            )?;
+            w.write(XmlEvent::characters(creator))?;
+            w.write(XmlEvent::end_element())?; // </dc:creator>
+            write_refinement(w, &id, "role", Some("marc:relators"), "aut")?;
+            if let Some(file_as) = meta.file_as(creator) {
+                write_refinement(w, &id, "file-as", None, file_as)?;
+            }
+        }
+        for (n, contributor) in meta.contributors.iter().flatten().enumerate() {
+            let id = format!("contributor-{}", n + 1);
+            w.write(
+                XmlEvent::start_element(Name::prefixed("contributor", NS_DC_PREFIX))
+                    .attr("id", &id),
+            )?;
+            w.write(XmlEvent::characters(&contributor.name))?;
+            w.write(XmlEvent::end_element())?; // </dc:contributor>
+            if let Some(role) = &contributor.role {
+                write_refinement(w, &id, "role", Some("marc:relators"), role.relator_code())?;
+            }
+            if let Some(file_as) = meta.file_as(&contributor.name) {
+                write_refinement(w, &id, "file-as", None, file_as)?;
+            }
+        }
+        if let Some(publisher) = &meta.publisher {
+            write_dc_element(w, "publisher", publisher)?;
+        }
+        if let Some(date) = &meta.date {
+            write_dc_element(w, "date", date)?;
+        }
+        if let Some(description) = &meta.description {
+            write_dc_element(w, "description", description)?;
+        }
+        for subject in meta.subjects.iter().flatten() {
+            write_dc_element(w, "subject", subject)?;
+        }
+        if let Some(rights) = &meta.rights {
+            write_dc_element(w, "rights", rights)?;
+        }
+
+        if let Some((series, position)) = &self.series {
+            let position = position.to_string();
+            w.write(
+                XmlEvent::start_element("meta")
+                    .attr("property", "belongs-to-collection")
+                    .attr("id", "series"),
+            )?;
+            w.write(XmlEvent::characters(series))?;
+            w.write(XmlEvent::end_element())?; // </meta>
+            write_refinement(w, "series", "collection-type", None, "series")?;
+            write_refinement(w, "series", "group-position", None, &position)?;
+
+            // Calibre does not read collections, and uses its own metadata for series instead
+            w.write(
+                XmlEvent::start_element("meta")
+                    .attr("name", "calibre:series")
+                    .attr("content", series),
+            )?;
+            w.write(XmlEvent::end_element())?; // </meta>
+            w.write(
+                XmlEvent::start_element("meta")
+                    .attr("name", "calibre:series_index")
+                    .attr("content", &position),
+            )?;
+            w.write(XmlEvent::end_element())?; // </meta>
         }
 
         w.write(XmlEvent::start_element("meta").attr("property", "dcterms:modified"))?;
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use xml::{namespace::NS_NO_PREFIX, writer::XmlEvent, EventWriter};
+
+use super::chapter_href;
+use crate::bookir::nav::{NavHeading, NavNode, NavTree};
+
+/// The EPUB 2 table of contents, for reading systems that do not support the navigation document
+pub const NCX_DOCUMENT: &str = "toc.ncx";
+
+pub const NCX_MEDIA: &str = "application/x-dtbncx+xml";
+
+pub const NS_NCX_URI: &str = "http://www.daisy.org/z3986/2005/ncx/";
+
+/// The manifest id of [`NCX_DOCUMENT`], which the spine refers to
+pub const NCX_ID: &str = "ncx";
+
+const NCX_VERSION: &str = "2005-1";
+
+/// The document `node` links to. Headings and draft chapters link to the first chapter under them, as every navPoint needs a target
+fn target(node: &NavNode) -> Option<String> {
+    match &node.heading {
+        NavHeading::Chapter(_, chapter) => Some(chapter_href(chapter)),
+        _ => node
+            .children
+            .iter()
+            .flat_map(NavTree::nested)
+            .find_map(|node| match &node.heading {
+                NavHeading::Chapter(_, chapter) => Some(chapter_href(chapter)),
+                _ => None,
+            }),
+    }
+}
+
+/// The number of levels of navPoints written for `tree`
+fn depth(tree: &NavTree) -> usize {
+    tree.iter()
+        .filter(|node| target(node).is_some())
+        .map(|node| 1 + node.children.as_ref().map_or(0, depth))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Numbers the navPoints of the NCX
+#[derive(Default)]
+struct PlayOrder {
+    /// The playOrder of each target. NavPoints with the same target must have the same playOrder
+    orders: HashMap<String, usize>,
+    points: usize,
+}
+
+fn write_nav_points<W: std::io::Write>(
+    tree: &NavTree,
+    order: &mut PlayOrder,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    for node in tree {
+        let Some(href) = target(node) else {
+            continue;
+        };
+        let label = match &node.heading {
+            NavHeading::Chapter(title, _)
+            | NavHeading::UnboundChapter(title)
+            | NavHeading::Heading(title) => title,
+        };
+
+        order.points += 1;
+        let next = order.orders.len() + 1;
+        let play_order = *order.orders.entry(href.clone()).or_insert(next);
+
+        w.write(
+            XmlEvent::start_element("navPoint")
+                .attr("id", &format!("navpoint-{}", order.points))
+                .attr("playOrder", &play_order.to_string()),
+        )?;
+        w.write(XmlEvent::start_element("navLabel"))?;
+        w.write(XmlEvent::start_element("text"))?;
+        w.write(XmlEvent::characters(label))?;
+        w.write(XmlEvent::end_element())?; // </text>
+        w.write(XmlEvent::end_element())?; // </navLabel>
+        w.write(XmlEvent::start_element("content").attr("src", &href))?;
+        w.write(XmlEvent::end_element())?; // </content>
+        if let Some(children) = &node.children {
+            write_nav_points(children, order, w)?;
+        }
+        w.write(XmlEvent::end_element())?; // </navPoint>
+    }
+    Ok(())
+}
+
+fn write_meta<W: std::io::Write>(
+    w: &mut EventWriter<W>,
+    name: &str,
+    content: &str,
+) -> xml::writer::Result<()> {
+    w.write(
+        XmlEvent::start_element("meta")
+            .attr("name", name)
+            .attr("content", content),
+    )?;
+    w.write(XmlEvent::end_element()) // </meta>
+}
+
+/// Writes the NCX for `tree`. `uid` must be the unique identifier of the package
+pub fn write_ncx<W: std::io::Write>(
+    tree: &NavTree,
+    title: &str,
+    uid: &str,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    w.write(XmlEvent::StartDocument {
+        version: xml::common::XmlVersion::Version10,
+        encoding: Some("UTF-8"),
+        standalone: None,
+    })?;
+    w.write(
+        XmlEvent::start_element("ncx")
+            .ns(NS_NO_PREFIX, NS_NCX_URI)
+            .attr("version", NCX_VERSION),
+    )?;
+
+    w.write(XmlEvent::start_element("head"))?;
+    write_meta(w, "dtb:uid", uid)?;
+    write_meta(w, "dtb:depth", &depth(tree).max(1).to_string())?;
+    write_meta(w, "dtb:totalPageCount", "0")?;
+    write_meta(w, "dtb:maxPageNumber", "0")?;
+    w.write(XmlEvent::end_element())?; // </head>
+
+    w.write(XmlEvent::start_element("docTitle"))?;
+    w.write(XmlEvent::start_element("text"))?;
+    w.write(XmlEvent::characters(title))?;
+    w.write(XmlEvent::end_element())?; // </text>
+    w.write(XmlEvent::end_element())?; // </docTitle>
+
+    w.write(XmlEvent::start_element("navMap"))?;
+    write_nav_points(tree, &mut PlayOrder::default(), w)?;
+    w.write(XmlEvent::end_element())?; // </navMap>
+    w.write(XmlEvent::end_element()) // </ncx>
+}
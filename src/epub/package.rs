@@ -5,6 +5,7 @@ use xml::{namespace::NS_NO_PREFIX, writer::XmlEvent, EventWriter};
 use super::{
     config::PackageId,
     info::{EpubFileInfo, EPUB_UNIQUE_IDENTIFIER_ID},
+    ncx::NCX_MEDIA,
 };
 
 pub use super::info::{NS_DC_PREFIX, NS_DC_URI};
@@ -116,6 +117,19 @@ impl EpubPackage {
         )?;
         writer.write(XmlEvent::start_element("metadata"))?;
         self.info.write_metadata(writer)?;
+        if let Some(cover) = self
+            .manifest
+            .iter()
+            .find(|item| item.properties.contains(&ItemProperty::CoverImage))
+        {
+            // EPUB 2 reading systems find the cover image through this entry instead of the manifest property
+            writer.write(
+                XmlEvent::start_element("meta")
+                    .attr("name", "cover")
+                    .attr("content", &cover.id),
+            )?;
+            writer.write(XmlEvent::end_element())?; // </meta>
+        }
         writer.write(XmlEvent::end_element())?; // </metadata>
         writer.write(XmlEvent::start_element("manifest"))?;
         for item in &self.manifest {
@@ -123,7 +137,16 @@ impl EpubPackage {
         }
         writer.write(XmlEvent::end_element())?; // </manifest>
 
-        writer.write(XmlEvent::start_element("spine"))?;
+        let mut spine = XmlEvent::start_element("spine");
+        // EPUB 2 reading systems find the NCX through the spine
+        if let Some(ncx) = self
+            .manifest
+            .iter()
+            .find(|item| item.media_type == NCX_MEDIA)
+        {
+            spine = spine.attr("toc", &ncx.id);
+        }
+        writer.write(spine)?;
         for item in &self.manifest {
             item.serialize_spine(writer)?;
         }
@@ -1 +1,559 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Seek, Write},
+    path::Path,
+};
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ring::{
+    digest::{self, Digest, SHA256},
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair, UnparsedPublicKey, VerificationAlgorithm,
+        ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING, ED25519,
+        RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_SHA256,
+    },
+};
+use xml::reader::{ParserConfig, XmlEvent};
+use zip::{result::ZipError, ZipArchive};
+
+use super::{
+    config::{SignatureKeyPair, SignaturePublicKey},
+    info::NS_CONTAINER_URI,
+};
+use crate::helpers;
+
+/// The file of the container that holds its signatures
+pub const SIGNATURES_FILE: &str = "META-INF/signatures.xml";
+
+pub const NS_DSIG_URI: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+const C14N_ALGORITHM: &str = "http://www.w3.org/TR/2001/REC-xml-c14n-20010315";
+const SHA256_ALGORITHM: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const RSA_SHA256_ALGORITHM: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const ECDSA_SHA256_ALGORITHM: &str = "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256";
+const ED25519_ALGORITHM: &str = "http://www.w3.org/2021/04/xmldsig-more#eddsa-ed25519";
+const MANIFEST_TYPE: &str = "http://www.w3.org/2000/09/xmldsig#Manifest";
+
+/// The id of the `Manifest` that lists the digest of every signed file. The signature covers the manifest, rather than the files themselves
+const MANIFEST_ID: &str = "manifest";
+
+const DER_INTEGER: u8 = 0x02;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+/// The explicit tag of the version of an X.509 certificate
+const DER_CERTIFICATE_VERSION: u8 = 0xa0;
+
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn malformed_der() -> io::Error {
+    invalid_data("malformed DER encoding in a key or certificate".to_string())
+}
+
+/// Splits the first DER element off of `input`, returning its tag, its contents, and the rest of the input
+fn der_element(input: &[u8]) -> io::Result<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first().ok_or_else(malformed_der)?;
+    let (&len, mut rest) = rest.split_first().ok_or_else(malformed_der)?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let octets = (len & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return Err(malformed_der());
+        }
+        let (len, after) = rest.split_at(octets);
+        rest = after;
+        len.iter().fold(0, |len, &octet| len << 8 | octet as usize)
+    };
+    if rest.len() < len {
+        return Err(malformed_der());
+    }
+    let (contents, rest) = rest.split_at(len);
+    Ok((tag, contents, rest))
+}
+
+/// Like [`der_element`], but fails unless the element has the tag `expected`. Returns the contents and the rest of the input
+fn der_expect(input: &[u8], expected: u8) -> io::Result<(&[u8], &[u8])> {
+    match der_element(input)? {
+        (tag, contents, rest) if tag == expected => Ok((contents, rest)),
+        _ => Err(malformed_der()),
+    }
+}
+
+/// Reads a key or certificate, which is either PEM or DER encoded. Returns the label of the PEM file (such as `PRIVATE KEY`), and the DER encoding
+fn read_key_file(path: &Path) -> io::Result<(Option<String>, Vec<u8>)> {
+    let content = fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read `{}`: {e}", path.display())))?;
+    let Some(pem) = std::str::from_utf8(&content)
+        .ok()
+        .and_then(|text| text.trim_start().strip_prefix("-----BEGIN "))
+    else {
+        return Ok((None, content));
+    };
+
+    let invalid_pem = |msg: String| invalid_data(format!("`{}` {msg}", path.display()));
+    let (label, rest) = pem
+        .split_once("-----")
+        .ok_or_else(|| invalid_pem("is not a valid PEM file".to_string()))?;
+    let body = rest
+        .split("-----END")
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    let der = BASE64
+        .decode(body)
+        .map_err(|e| invalid_pem(format!("is not a valid PEM file: {e}")))?;
+    Ok((Some(label.to_string()), der))
+}
+
+/// Escapes an attribute value, as in canonical XML
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#x9;")
+        .replace('\n', "&#xA;")
+        .replace('\r', "&#xD;")
+}
+
+/// Percent-encodes the characters of a path in the container that cannot appear in a URI
+fn path_uri(path: &str) -> String {
+    let mut uri = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/!$&'()*+,;=:@".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri
+}
+
+/// A signed file, and its digest
+struct FileReference {
+    uri: String,
+    digest: Vec<u8>,
+}
+
+// The signed elements are written in canonical form (as by Canonical XML 1.0), so that the signed bytes are exactly those written.
+// In the canonical form, they also declare the default namespace inherited from `<Signature>`
+
+fn digest_xml(digest: &[u8]) -> String {
+    format!(
+        "<DigestMethod Algorithm=\"{SHA256_ALGORITHM}\"></DigestMethod><DigestValue>{}</DigestValue>",
+        BASE64.encode(digest)
+    )
+}
+
+fn manifest_xml(files: &[FileReference], canonical: bool) -> String {
+    let ns = if canonical {
+        format!(" xmlns=\"{NS_DSIG_URI}\"")
+    } else {
+        String::new()
+    };
+    let mut xml = format!("<Manifest{ns} Id=\"{MANIFEST_ID}\">");
+    for file in files {
+        xml.push_str(&format!(
+            "<Reference URI=\"{}\">{}</Reference>",
+            escape_attr(&file.uri),
+            digest_xml(&file.digest)
+        ));
+    }
+    xml.push_str("</Manifest>");
+    xml
+}
+
+fn signed_info_xml(method: &str, manifest_digest: &[u8], canonical: bool) -> String {
+    let ns = if canonical {
+        format!(" xmlns=\"{NS_DSIG_URI}\"")
+    } else {
+        String::new()
+    };
+    format!(
+        "<SignedInfo{ns}><CanonicalizationMethod Algorithm=\"{C14N_ALGORITHM}\"></CanonicalizationMethod><SignatureMethod Algorithm=\"{}\"></SignatureMethod><Reference Type=\"{MANIFEST_TYPE}\" URI=\"#{MANIFEST_ID}\">{}</Reference></SignedInfo>",
+        escape_attr(method),
+        digest_xml(manifest_digest)
+    )
+}
+
+enum KeyPair {
+    Rsa(RsaKeyPair),
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// A private key that epub files are signed with. Supports RSA, ECDSA (with the P-256 curve), and Ed25519 keys
+pub struct SigningKey {
+    key: KeyPair,
+    /// The DER encoded certificate of the public key, which is included in the signature
+    certificate: Option<Vec<u8>>,
+}
+
+impl SigningKey {
+    /// Loads the private key of `pair`, and its certificate if the public key is one. The paths are relative to `root`
+    pub fn load(pair: &SignatureKeyPair, root: &Path) -> io::Result<Self> {
+        let path = root.join(&pair.private);
+        let rejected = |e: ring::error::KeyRejected| {
+            invalid_data(format!(
+                "the private key `{}` was rejected: {e}",
+                path.display()
+            ))
+        };
+
+        let (label, der) = read_key_file(&path)?;
+        let key = match label.as_deref() {
+            Some("RSA PRIVATE KEY") => KeyPair::Rsa(RsaKeyPair::from_der(&der).map_err(rejected)?),
+            Some("PRIVATE KEY") | None => {
+                // A PKCS#8 PrivateKeyInfo starts with its version, followed by the algorithm of the key
+                let (info, _) = der_expect(&der, DER_SEQUENCE)?;
+                let (_, rest) = der_expect(info, DER_INTEGER)?;
+                let (algorithm, _) = der_expect(rest, DER_SEQUENCE)?;
+                let (oid, _) = der_expect(algorithm, DER_OID)?;
+                match oid {
+                    OID_RSA_ENCRYPTION => {
+                        KeyPair::Rsa(RsaKeyPair::from_pkcs8(&der).map_err(rejected)?)
+                    }
+                    OID_EC_PUBLIC_KEY => KeyPair::Ecdsa(
+                        EcdsaKeyPair::from_pkcs8(
+                            &ECDSA_P256_SHA256_FIXED_SIGNING,
+                            &der,
+                            &SystemRandom::new(),
+                        )
+                        .map_err(rejected)?,
+                    ),
+                    OID_ED25519 => KeyPair::Ed25519(
+                        Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(rejected)?,
+                    ),
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "the private key `{}` is not an RSA, ECDSA, or Ed25519 key",
+                            path.display()
+                        )))
+                    }
+                }
+            }
+            Some(label) => {
+                return Err(invalid_data(format!(
+                    "`{}` contains a {label}, instead of a PKCS#8 or RSA private key",
+                    path.display()
+                )))
+            }
+        };
+
+        let certificate = match &pair.public {
+            SignaturePublicKey::Certificate { cert } => Some(read_key_file(&root.join(cert))?.1),
+            _ => None,
+        };
+
+        Ok(Self { key, certificate })
+    }
+
+    /// The URI of the signature algorithm
+    fn method(&self) -> &'static str {
+        match &self.key {
+            KeyPair::Rsa(_) => RSA_SHA256_ALGORITHM,
+            KeyPair::Ecdsa(_) => ECDSA_SHA256_ALGORITHM,
+            KeyPair::Ed25519(_) => ED25519_ALGORITHM,
+        }
+    }
+
+    /// Signs `message`. RSA and Ed25519 signatures are deterministic, but each ECDSA signature uses a fresh random nonce,
+    /// so an output signed with an ECDSA key differs between builds even when the build is reproducible
+    fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let failed = |_| io::Error::other("signing the epub failed");
+        let rng = SystemRandom::new();
+        match &self.key {
+            KeyPair::Rsa(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .map_err(failed)?;
+                Ok(signature)
+            }
+            KeyPair::Ecdsa(key) => Ok(key.sign(&rng, message).map_err(failed)?.as_ref().to_vec()),
+            KeyPair::Ed25519(key) => Ok(key.sign(message).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Writes `META-INF/signatures.xml`, signing each file in `files` (the path and digest of the files of the container) other than `mimetype`
+pub fn write_signatures<W: Write>(
+    files: &[(String, Digest)],
+    key: &SigningKey,
+    w: &mut W,
+) -> io::Result<()> {
+    let references = files
+        .iter()
+        .filter(|(name, _)| name != "mimetype" && name != SIGNATURES_FILE)
+        .map(|(name, digest)| FileReference {
+            uri: path_uri(name),
+            digest: digest.as_ref().to_vec(),
+        })
+        .collect::<Vec<_>>();
+
+    let manifest_digest = digest::digest(&SHA256, manifest_xml(&references, true).as_bytes());
+    let signature =
+        key.sign(signed_info_xml(key.method(), manifest_digest.as_ref(), true).as_bytes())?;
+
+    write!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    write!(
+        w,
+        "<signatures xmlns=\"{NS_CONTAINER_URI}\"><Signature xmlns=\"{NS_DSIG_URI}\" Id=\"signature\">"
+    )?;
+    w.write_all(signed_info_xml(key.method(), manifest_digest.as_ref(), false).as_bytes())?;
+    write!(
+        w,
+        "<SignatureValue>{}</SignatureValue>",
+        BASE64.encode(signature)
+    )?;
+    if let Some(certificate) = &key.certificate {
+        write!(
+            w,
+            "<KeyInfo><X509Data><X509Certificate>{}</X509Certificate></X509Data></KeyInfo>",
+            BASE64.encode(certificate)
+        )?;
+    }
+    write!(
+        w,
+        "<Object>{}</Object></Signature></signatures>",
+        manifest_xml(&references, false)
+    )
+}
+
+/// A public key that the signatures of epub files are verified with
+pub struct VerifyingKey {
+    /// The URI of the signature algorithm
+    method: &'static str,
+    algorithm: &'static dyn VerificationAlgorithm,
+    key: Vec<u8>,
+}
+
+impl VerifyingKey {
+    /// Loads `key`, relative to `root`. OpenPGP keys are not supported
+    pub fn load(key: &SignaturePublicKey, root: &Path) -> io::Result<Self> {
+        match key {
+            SignaturePublicKey::Regular { public } => {
+                Self::from_spki(&read_key_file(&root.join(public))?.1)
+            }
+            SignaturePublicKey::Certificate { cert } => {
+                Self::from_certificate(&read_key_file(&root.join(cert))?.1)
+            }
+            SignaturePublicKey::Pgp { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "OpenPGP public keys are not supported",
+            )),
+        }
+    }
+
+    /// Reads a DER encoded SubjectPublicKeyInfo
+    pub fn from_spki(der: &[u8]) -> io::Result<Self> {
+        let (spki, _) = der_expect(der, DER_SEQUENCE)?;
+        let (algorithm, rest) = der_expect(spki, DER_SEQUENCE)?;
+        let (oid, params) = der_expect(algorithm, DER_OID)?;
+        let (bits, _) = der_expect(rest, DER_BIT_STRING)?;
+        // The first octet of a bit string is the number of unused bits, which is 0 for keys
+        let key = bits.get(1..).ok_or_else(malformed_der)?.to_vec();
+
+        let (method, algorithm): (_, &'static dyn VerificationAlgorithm) = match oid {
+            OID_RSA_ENCRYPTION => (RSA_SHA256_ALGORITHM, &RSA_PKCS1_2048_8192_SHA256),
+            OID_EC_PUBLIC_KEY
+                if der_expect(params, DER_OID).is_ok_and(|(curve, _)| curve == OID_P256) =>
+            {
+                (ECDSA_SHA256_ALGORITHM, &ECDSA_P256_SHA256_FIXED)
+            }
+            OID_ED25519 => (ED25519_ALGORITHM, &ED25519),
+            _ => {
+                return Err(invalid_data(
+                    "the public key is not an RSA, ECDSA (P-256), or Ed25519 key".to_string(),
+                ))
+            }
+        };
+        Ok(Self {
+            method,
+            algorithm,
+            key,
+        })
+    }
+
+    /// Reads the public key of a DER encoded X.509 certificate
+    pub fn from_certificate(der: &[u8]) -> io::Result<Self> {
+        let (certificate, _) = der_expect(der, DER_SEQUENCE)?;
+        let (tbs, _) = der_expect(certificate, DER_SEQUENCE)?;
+        let mut rest = tbs;
+        if let (DER_CERTIFICATE_VERSION, _, after) = der_element(rest)? {
+            rest = after;
+        }
+        // The serial number, signature algorithm, issuer, validity, and subject precede the public key
+        for _ in 0..5 {
+            rest = der_element(rest)?.2;
+        }
+        let after = der_element(rest)?.2;
+        Self::from_spki(&rest[..rest.len() - after.len()])
+    }
+}
+
+/// The contents of a `signatures.xml` written by [`write_signatures`]
+#[derive(Default)]
+struct ParsedSignature {
+    method: String,
+    manifest_digest: Vec<u8>,
+    signature: Vec<u8>,
+    files: Vec<FileReference>,
+}
+
+fn decode_base64(text: &str) -> io::Result<Vec<u8>> {
+    let text = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    BASE64
+        .decode(text)
+        .map_err(|e| invalid_data(format!("invalid base64 in `{SIGNATURES_FILE}`: {e}")))
+}
+
+impl ParsedSignature {
+    fn parse(xml: &[u8]) -> io::Result<Self> {
+        let mut parsed = Self::default();
+        let mut stack = Vec::new();
+        let mut uri = String::new();
+        let mut text = String::new();
+
+        for event in ParserConfig::new().trim_whitespace(true).create_reader(xml) {
+            match event
+                .map_err(|e| invalid_data(format!("`{SIGNATURES_FILE}` is not well-formed: {e}")))?
+            {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    let attr = |local: &str| {
+                        attributes
+                            .iter()
+                            .find(|attr| attr.name.local_name == local)
+                            .map_or("", |attr| &*attr.value)
+                    };
+                    match &*name.local_name {
+                        "SignatureMethod" => parsed.method = attr("Algorithm").to_string(),
+                        "DigestMethod" if attr("Algorithm") != SHA256_ALGORITHM => {
+                            return Err(invalid_data(format!(
+                                "unsupported digest algorithm `{}`",
+                                attr("Algorithm")
+                            )))
+                        }
+                        "Reference" => uri = attr("URI").to_string(),
+                        _ => {}
+                    }
+                    stack.push(name.local_name);
+                    text.clear();
+                }
+                XmlEvent::Characters(chars) => text.push_str(&chars),
+                XmlEvent::EndElement { name } => {
+                    stack.pop();
+                    let in_signed_info = stack.iter().any(|name| name == "SignedInfo");
+                    match &*name.local_name {
+                        "DigestValue" if in_signed_info => {
+                            if uri != format!("#{MANIFEST_ID}") {
+                                return Err(invalid_data(format!(
+                                    "the signature does not refer to the manifest of `{SIGNATURES_FILE}`"
+                                )));
+                            }
+                            parsed.manifest_digest = decode_base64(&text)?;
+                        }
+                        "DigestValue" => parsed.files.push(FileReference {
+                            uri: uri.clone(),
+                            digest: decode_base64(&text)?,
+                        }),
+                        "SignatureValue" => parsed.signature = decode_base64(&text)?,
+                        // Only the first signature is verified
+                        "Signature" => break,
+                        _ => {}
+                    }
+                    text.clear();
+                }
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Verifies the signature of the epub file read from `reader` with `key`.
+///
+/// The epub must be signed by the private key of `key`, every file in the container other than `mimetype` must be signed,
+/// and no file may have changed since it was signed. Only signatures written by [`write_signatures`] can be verified
+pub fn verify_epub<R: Read + Seek>(reader: R, key: &VerifyingKey) -> io::Result<()> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut signatures = Vec::new();
+    match zip.by_name(SIGNATURES_FILE) {
+        Ok(mut file) => file.read_to_end(&mut signatures)?,
+        Err(ZipError::FileNotFound) => {
+            return Err(invalid_data(format!(
+                "the epub is not signed (`{SIGNATURES_FILE}` is missing)"
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let parsed = ParsedSignature::parse(&signatures)?;
+
+    if parsed.method != key.method {
+        return Err(invalid_data(format!(
+            "the epub is signed with `{}`, but the public key is for `{}`",
+            parsed.method, key.method
+        )));
+    }
+    let manifest_digest = digest::digest(&SHA256, manifest_xml(&parsed.files, true).as_bytes());
+    if manifest_digest.as_ref() != parsed.manifest_digest {
+        return Err(invalid_data(format!(
+            "the list of signed files in `{SIGNATURES_FILE}` has been modified"
+        )));
+    }
+    let signed_info = signed_info_xml(&parsed.method, &parsed.manifest_digest, true);
+    UnparsedPublicKey::new(key.algorithm, &key.key)
+        .verify(signed_info.as_bytes(), &parsed.signature)
+        .map_err(|_| {
+            invalid_data(
+                "the signature was not made by the private key of the public key".to_string(),
+            )
+        })?;
+
+    let mut unsigned = zip
+        .file_names()
+        .filter(|name| *name != "mimetype" && *name != SIGNATURES_FILE && !name.ends_with('/'))
+        .map(str::to_string)
+        .collect::<HashSet<_>>();
+    for file in &parsed.files {
+        let name = helpers::percent_decode(&file.uri);
+        let mut content = Vec::new();
+        match zip.by_name(&name) {
+            Ok(mut signed) => signed.read_to_end(&mut content)?,
+            Err(ZipError::FileNotFound) => {
+                return Err(invalid_data(format!(
+                    "`{name}` is signed, but is not in the container"
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if digest::digest(&SHA256, &content).as_ref() != file.digest {
+            return Err(invalid_data(format!(
+                "`{name}` has been modified since it was signed"
+            )));
+        }
+        unsigned.remove(&name);
+    }
+
+    match unsigned.iter().min() {
+        Some(name) => Err(invalid_data(format!("`{name}` is not signed"))),
+        None => Ok(()),
+    }
+}
@@ -1 +1,51 @@
 pub const DEFAULT_STYLE: &[u8] = include_bytes!("style/default-style.css");
+
+/// The path of [`DEFAULT_STYLE`] in the epub container
+pub const DEFAULT_STYLE_PATH: &str = "default-style.css";
+
+/// The path of the stylesheet of the highlighting theme in the epub container
+#[cfg(feature = "highlight")]
+pub const HIGHLIGHT_STYLE_PATH: &str = "highlight.css";
+
+pub const CSS_MEDIA: &str = "text/css";
+
+/// Finds the urls of the font files used by the `@font-face` rules of a stylesheet.
+///
+/// Remote fonts and `data:` urls are not included, as they are not files in the container
+pub fn font_face_urls(css: &str) -> Vec<String> {
+    let mut css = css;
+    let mut stripped = String::with_capacity(css.len());
+    while let Some((before, rest)) = css.split_once("/*") {
+        stripped.push_str(before);
+        css = rest.split_once("*/").map_or("", |(_, after)| after);
+    }
+    stripped.push_str(css);
+
+    let mut urls = Vec::new();
+    let mut rest = &*stripped;
+    while let Some(idx) = rest.find("@font-face") {
+        rest = &rest[idx..];
+        let Some((block, after)) = rest
+            .split_once('{')
+            .and_then(|(_, body)| body.split_once('}'))
+        else {
+            break;
+        };
+        rest = after;
+
+        let mut block = block;
+        while let Some((_, url)) = block.split_once("url(") {
+            let Some((url, after)) = url.split_once(')') else {
+                break;
+            };
+            block = after;
+            let url = url.trim().trim_matches(|c| c == '"' || c == '\'');
+            let url = url.split(['?', '#']).next().unwrap_or(url);
+            if !url.is_empty() && !url.contains("://") && !url.starts_with("data:") {
+                urls.push(url.to_string());
+            }
+        }
+    }
+
+    urls
+}
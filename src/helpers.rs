@@ -2,27 +2,67 @@ use std::{
     borrow::Cow,
     fs::{DirEntry, ReadDir},
     io,
-    path::Path,
+    path::{Component, Path, PathBuf},
 };
 
-use mdbook::BookItem;
+use mdbook::{book::Chapter, BookItem};
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag};
 
-pub fn name_to_id(mut x: &str) -> String {
-    if let Some((l, r)) = x.split_once('{') {
-        if let Some((_, r)) = r.split_once('#') {
-            let (l, _) = r.split_once('}').unwrap();
+/// An attribute block (`{#id .class key=value}`), as used by the heading attribute extension
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AttributeBlock<'a> {
+    pub id: Option<&'a str>,
+    pub classes: Vec<&'a str>,
+    /// Attributes other than the id and classes. Attributes without a value (`{key}`) have the value `None`
+    pub attrs: Vec<(&'a str, Option<&'a str>)>,
+}
 
-            let val = l
-                .split_once(|c: char| c.is_whitespace())
-                .map(|(l, _)| l)
-                .unwrap_or(l);
+/// Splits the attribute block in `text` (such as a part or chapter title in `SUMMARY.md`) from the text before it.
+///
+/// The block starts at the first `{` and ends at the following `}`, or at the end of `text` if it is never closed.
+/// Anything after the block is ignored. Output ids come from the block (see [`name_to_id`]), so it is found where it always has been,
+/// even in titles that have text after it.
+///
+/// Returns `text` unchanged and `None` if `text` has no attribute block.
+/// If the block has more than one id, the first one is used
+pub fn split_attribute_block(text: &str) -> (&str, Option<AttributeBlock<'_>>) {
+    let Some((before, rest)) = text.split_once('{') else {
+        return (text, None);
+    };
+    let block = rest.split_once('}').map_or(rest, |(block, _)| block);
 
-            return val.to_string();
+    let mut attrs = AttributeBlock::default();
+    for spec in block.split_whitespace() {
+        if let Some(id) = spec.strip_prefix('#') {
+            attrs.id.get_or_insert(id);
+        } else if let Some(class) = spec.strip_prefix('.') {
+            attrs.classes.push(class);
+        } else if let Some((key, value)) = spec.split_once('=') {
+            attrs.attrs.push((key, Some(value)));
         } else {
-            x = l
+            attrs.attrs.push((spec, None));
         }
     }
 
+    (before, Some(attrs))
+}
+
+/// Removes the attribute block in `title`, if any, giving the text that is displayed to readers
+pub fn display_title(title: &str) -> &str {
+    split_attribute_block(title).0.trim()
+}
+
+/// Computes the id for a part or chapter title.
+///
+/// If the title has an attribute block with an id (such as `Part A {#part-a .class}`), that id is used.
+/// Otherwise, the id is the title before the attribute block (see [`split_attribute_block`]), with runs of whitespace replaced by `-`,
+/// letters converted to lower case, and characters other than letters, digits, `_`, and `-` removed.
+pub fn name_to_id(x: &str) -> String {
+    let (x, attrs) = split_attribute_block(x);
+    if let Some(id) = attrs.and_then(|attrs| attrs.id) {
+        return id.to_string();
+    }
+
     let mut s = String::with_capacity(x.len());
     let mut ws_skip = false;
 
@@ -44,6 +84,117 @@ pub fn name_to_id(mut x: &str) -> String {
     s
 }
 
+/// Computes the output id of a chapter.
+///
+/// If the first thing in the chapter is an h1 heading with an explicit id (`# Title {#id}`), that id is used.
+/// Otherwise, the id is derived from the chapter name in `SUMMARY.md`, as by [`name_to_id`].
+pub fn chapter_output_id(ch: &Chapter) -> String {
+    let mut parser = Parser::new_ext(&ch.content, Options::ENABLE_HEADING_ATTRIBUTES);
+
+    match parser.next() {
+        Some(Event::Start(Tag::Heading {
+            level: HeadingLevel::H1,
+            id: Some(id),
+            ..
+        })) => id.to_string(),
+        _ => name_to_id(&ch.name),
+    }
+}
+
+/// The language of a chapter, if it differs from the language of the book.
+///
+/// This is the `lang` attribute of the first thing in the chapter, if it is an h1 heading (`# Titre {lang=fr}`)
+pub fn chapter_language(ch: &Chapter) -> Option<String> {
+    let mut parser = Parser::new_ext(&ch.content, Options::ENABLE_HEADING_ATTRIBUTES);
+
+    match parser.next() {
+        Some(Event::Start(Tag::Heading {
+            level: HeadingLevel::H1,
+            attrs,
+            ..
+        })) => attrs
+            .into_iter()
+            .find(|(key, _)| &**key == "lang")
+            .and_then(|(_, value)| value)
+            .map(|lang| lang.to_string()),
+        _ => None,
+    }
+}
+
+/// Resolves `.` and `..` components of a relative path, without accessing the filesystem.
+///
+/// Returns `None` if the path is absolute, or refers to a location outside of the directory it is relative to
+pub fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Resolves `href`, a link or image in the chapter at `chapter_path`, to the file it refers to.
+/// Both paths are relative to the book source directory, with `/` separators, and any fragment or query of `href` is ignored.
+///
+/// Returns `None` for urls with a scheme (such as `https:` or `mailto:`), links within the chapter, and paths outside of the book source directory
+pub fn resolve_source_href(chapter_path: &str, href: &str) -> Option<String> {
+    let path = href.split(['#', '?']).next().unwrap_or(href);
+    if path.is_empty()
+        || path
+            .split('/')
+            .next()
+            .is_some_and(|first| first.contains(':'))
+    {
+        return None;
+    }
+
+    let base = match path.strip_prefix('/') {
+        Some(_) => Path::new(""),
+        None => Path::new(chapter_path).parent().unwrap_or(Path::new("")),
+    };
+    let resolved = normalize_path(&base.join(percent_decode(path.trim_start_matches('/'))))?;
+    Some(resolved.to_string_lossy().replace('\\', "/"))
+}
+
+/// Replaces the extension of the `/` separated path `path` with `ext`
+pub fn with_extension(path: &str, ext: &str) -> String {
+    let mut path = Path::new(path).to_path_buf();
+    path.set_extension(ext);
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Decodes the `%XX` escapes of a url path or fragment. Invalid escapes are kept as they are
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 fn visit_chapters_impl<
     'a,
     S,
@@ -0,0 +1,138 @@
+use std::{io, sync::OnceLock};
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Style, Theme, ThemeSet},
+    html::{css_for_theme_with_class_style, ClassStyle},
+    parsing::{BasicScopeStackOp, ParseState, ScopeStack, SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+/// Prepended to the classes of highlighted code, so that they do not conflict with the classes of the book's stylesheets
+pub const CLASS_PREFIX: &str = "hl-";
+
+/// The class of the element holding highlighted code, which the stylesheet of the theme gives the colors of the theme
+pub const CODE_CLASS: &str = "hl-code";
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: CLASS_PREFIX,
+};
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Finds the bundled theme named `name`, such as `InspiredGitHub` or `base16-ocean.dark`
+pub fn find_theme(name: &str) -> io::Result<&'static Theme> {
+    themes().themes.get(name).ok_or_else(|| {
+        let names = themes()
+            .themes
+            .keys()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>();
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "unknown highlighting theme `{name}`, expected one of {}",
+                names.join(", ")
+            ),
+        )
+    })
+}
+
+/// Finds the bundled syntax for the language of a code block, by its name (such as `Rust`) or file extension (such as `rs`)
+fn find_syntax(lang: &str) -> Option<&'static SyntaxReference> {
+    if lang.is_empty() {
+        return None;
+    }
+    syntaxes().find_syntax_by_token(lang)
+}
+
+/// A piece of highlighted code. The scopes of the syntax are written as nested elements
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClassedToken<'a> {
+    /// Starts an element for a scope, with the given classes (the atoms of the scope, such as `hl-keyword hl-control hl-rust`)
+    Start(String),
+    /// Ends the innermost element
+    End,
+    Text(&'a str),
+}
+
+/// Splits `code` into the scopes of the syntax for `lang`, which are styled by the stylesheet of a theme (see [`theme_css`]).
+///
+/// Returns `None` if there is no bundled syntax for `lang`, in which case the code is written without highlighting
+pub fn classed_tokens<'a>(code: &'a str, lang: &str) -> Option<Vec<ClassedToken<'a>>> {
+    let mut state = ParseState::new(find_syntax(lang)?);
+    let mut stack = ScopeStack::new();
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+
+    for line in LinesWithEndings::from(code) {
+        let mut pos = 0;
+        for (idx, op) in state.parse_line(line, syntaxes()).ok()? {
+            if idx > pos {
+                tokens.push(ClassedToken::Text(&line[pos..idx]));
+                pos = idx;
+            }
+            stack
+                .apply_with_hook(&op, |op, _| match op {
+                    BasicScopeStackOp::Push(scope) => {
+                        let classes = scope
+                            .build_string()
+                            .split('.')
+                            .map(|atom| format!("{CLASS_PREFIX}{atom}"))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        tokens.push(ClassedToken::Start(classes));
+                        depth += 1;
+                    }
+                    BasicScopeStackOp::Pop => {
+                        tokens.push(ClassedToken::End);
+                        depth = depth.saturating_sub(1);
+                    }
+                })
+                .ok()?;
+        }
+        if pos < line.len() {
+            tokens.push(ClassedToken::Text(&line[pos..]));
+        }
+    }
+    tokens.extend(std::iter::repeat_n(ClassedToken::End, depth));
+
+    Some(tokens)
+}
+
+/// Splits each line of `code` into runs of text, styled by `theme` for the syntax of `lang`. The lines do not include their line endings.
+///
+/// Returns `None` if there is no bundled syntax for `lang`
+pub fn styled_lines<'a>(
+    code: &'a str,
+    lang: &str,
+    theme: &Theme,
+) -> Option<Vec<Vec<(Style, &'a str)>>> {
+    let mut highlighter = HighlightLines::new(find_syntax(lang)?, theme);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let runs = highlighter.highlight_line(line, syntaxes()).ok()?;
+            Some(
+                runs.into_iter()
+                    .map(|(style, text)| (style, text.trim_end_matches(['\r', '\n'])))
+                    .filter(|(_, text)| !text.is_empty())
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// The stylesheet giving code written with [`classed_tokens`] the colors of `theme`.
+/// The element holding the code should have the class [`CODE_CLASS`], for the foreground and background of the theme
+pub fn theme_css(theme: &Theme) -> io::Result<String> {
+    css_for_theme_with_class_style(theme, CLASS_STYLE)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,376 @@
+use std::{borrow::Cow, collections::HashMap, io, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use xml::{
+    escape::{escape_str_attribute, escape_str_pcdata},
+    writer::XmlEvent,
+    EmitterConfig, EventWriter,
+};
+
+use crate::{
+    bookir::{nav::NavHeading, render::Renderer, Book, BookChapter, ExtraItem},
+    helpers::{self, name_to_id, normalize_path},
+    xhtml::{self, ChapterLinks, DocumentContext, FootnotePlacement},
+};
+
+use config::HtmlConfig;
+
+pub mod config;
+
+/// The default stylesheet of the epub backend, so that the book looks the same in both outputs
+pub const DEFAULT_STYLE: &str = include_str!("epub/style/default-style.css");
+
+/// Lays out the page around the book, such as the width of the text and the table of contents
+pub const PAGE_STYLE: &str = include_str!("html/page-style.css");
+
+/// Elements that have no end tag in html. The xhtml writer ends every element, so their end tags are removed
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// The id of the section that footnotes are collected into with [`FootnotePlacement::EndOfBook`]
+const NOTES_ID: &str = "notes";
+
+/// The contents of `file` as a `data:` url
+fn data_url(file: &ExtraItem) -> io::Result<String> {
+    let content = std::fs::read(&file.src_path)?;
+    Ok(format!(
+        "data:{};base64,{}",
+        file.content_type,
+        STANDARD.encode(content)
+    ))
+}
+
+/// Finds the file at `path` (relative to the book source directory) among the files of the book source
+fn find_source_file<'a>(path: &Path, extra_files: &'a [ExtraItem]) -> Option<&'a ExtraItem> {
+    let path = normalize_path(path)?;
+    extra_files.iter().find(|file| file.dest_path == path)
+}
+
+/// Replaces the `url()`s in `css` that refer to files of the book source (such as fonts) by `data:` urls.
+/// `dir` is the directory of the stylesheet, relative to the book source directory.
+///
+/// Other urls are kept as they are
+fn embed_css_urls(css: &str, dir: &Path, extra_files: &[ExtraItem]) -> io::Result<String> {
+    let mut embedded = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(idx) = rest.find("url(") {
+        let (before, after) = rest.split_at(idx + "url(".len());
+        embedded.push_str(before);
+        let Some(end) = after.find(')') else {
+            rest = after;
+            break;
+        };
+        let raw = &after[..end];
+        rest = &after[end..];
+
+        let url = raw.trim().trim_matches(|c| c == '"' || c == '\'');
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let file = Some(path)
+            .filter(|path| !path.is_empty() && !path.contains(':'))
+            .and_then(|path| {
+                find_source_file(&dir.join(helpers::percent_decode(path)), extra_files)
+            });
+        match file {
+            Some(file) => {
+                embedded.push('"');
+                embedded.push_str(&data_url(file)?);
+                embedded.push('"');
+            }
+            None => embedded.push_str(raw),
+        }
+    }
+    embedded.push_str(rest);
+    Ok(embedded)
+}
+
+/// Reads a stylesheet from `config.css`, embedding the files it refers to
+fn read_stylesheet(css: &Path, extra_files: &[ExtraItem]) -> io::Result<String> {
+    let file = find_source_file(css, extra_files).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("stylesheet `{}` is not in the book source", css.display()),
+        )
+    })?;
+
+    let content = std::fs::read_to_string(&file.src_path)?;
+    // The stylesheet is written into a `<style>` element, which ends at the first `</style`
+    if content.to_ascii_lowercase().contains("</style") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "stylesheet `{}` contains `</style`, and cannot be embedded",
+                css.display()
+            ),
+        ));
+    }
+    embed_css_urls(
+        &content,
+        file.dest_path.parent().unwrap_or(Path::new("")),
+        extra_files,
+    )
+}
+
+/// Rewrites the xhtml written for the body of the document with html syntax, by removing the end tags of void elements (such as `</br>`).
+///
+/// This relies on the writer not using self-closing tags (which html ignores on other elements), and escaping `<` in text and attribute values
+fn html_syntax(xhtml: &str) -> String {
+    let mut html = xhtml.to_string();
+    for name in VOID_ELEMENTS {
+        html = html.replace(&format!("</{name}>"), "");
+    }
+    html
+}
+
+/// The chapters and parts of the book, with the state needed to write them into one document
+struct BodyWriter<'r, 'w, W: io::Write> {
+    config: &'r HtmlConfig,
+    links: &'r ChapterLinks,
+    embedded: &'r HashMap<String, String>,
+    #[cfg(feature = "highlight")]
+    highlight: bool,
+    /// The chapters whose footnotes are collected at the end of the document, with their titles
+    notes: Vec<(String, DocumentContext<'r>)>,
+    w: &'w mut EventWriter<W>,
+}
+
+impl<'r, W: io::Write> Renderer<'r> for BodyWriter<'r, '_, W> {
+    type Error = xml::writer::Error;
+
+    fn text(&mut self, text: &str) -> xml::writer::Result<()> {
+        self.w.write(XmlEvent::characters(text))
+    }
+
+    fn start_part(&mut self, title: &str) -> xml::writer::Result<()> {
+        self.w.write(
+            XmlEvent::start_element("section")
+                .attr("id", &format!("part-{}", name_to_id(title)))
+                .attr("class", "part"),
+        )?;
+        self.w.write(XmlEvent::start_element("h1"))?;
+        self.w.write(XmlEvent::characters(title))?;
+        self.w.write(XmlEvent::end_element()) // </h1>
+    }
+
+    fn end_part(&mut self, _: &str) -> xml::writer::Result<()> {
+        self.w.write(XmlEvent::end_element()) // </section>
+    }
+
+    /// Writes the chapter into a section, with a context of its own for its content
+    fn render_chapter(
+        &mut self,
+        title: &str,
+        chapter: &'r BookChapter<'r>,
+    ) -> xml::writer::Result<()> {
+        let mut cx = DocumentContext::for_chapter(chapter, self.config.footnotes)
+            .in_single_document()
+            .with_links(self.links)
+            .with_embedded(self.embedded);
+        #[cfg(feature = "highlight")]
+        {
+            cx.highlight = self.highlight;
+        }
+
+        let id = xhtml::section_id(&cx.source_path);
+        let mut start = XmlEvent::start_element("section")
+            .attr("id", &id)
+            .attr("class", "chapter");
+        if let Some(lang) = &chapter.lang {
+            start = start.attr("lang", lang);
+        }
+        self.w.write(start)?;
+        for elem in &chapter.content {
+            xhtml::write_rich_node(elem, &mut cx, self.w)?;
+        }
+        if self.config.footnotes == FootnotePlacement::EndOfChapter {
+            xhtml::write_footnotes(&mut cx, self.w)?;
+        }
+        self.w.write(XmlEvent::end_element())?; // </section>
+
+        if self.config.footnotes == FootnotePlacement::EndOfBook {
+            self.notes.push((title.to_string(), cx));
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> BodyWriter<'_, '_, W> {
+    /// Writes the section holding the footnotes of every chapter, with [`FootnotePlacement::EndOfBook`]
+    fn write_notes(&mut self) -> xml::writer::Result<()> {
+        if self.notes.iter().all(|(_, cx)| cx.notes.is_empty()) {
+            return Ok(());
+        }
+
+        self.w.write(
+            XmlEvent::start_element("section")
+                .attr("id", NOTES_ID)
+                .attr("class", "notes"),
+        )?;
+        self.w.write(XmlEvent::start_element("h1"))?;
+        self.w.write(XmlEvent::characters("Notes"))?;
+        self.w.write(XmlEvent::end_element())?; // </h1>
+        for (title, cx) in &mut self.notes {
+            if cx.notes.is_empty() {
+                continue;
+            }
+            self.w.write(XmlEvent::start_element("h2"))?;
+            self.w.write(XmlEvent::characters(title))?;
+            self.w.write(XmlEvent::end_element())?; // </h2>
+            xhtml::write_footnotes(cx, self.w)?;
+        }
+        self.w.write(XmlEvent::end_element()) // </section>
+    }
+}
+
+/// Writes the `<body>` of the document: the title of the book, the table of contents, and every chapter
+fn write_body<'r, W: io::Write>(
+    book: &'r Book<'r>,
+    body: &mut BodyWriter<'r, '_, W>,
+) -> xml::writer::Result<()> {
+    let w = &mut *body.w;
+    w.write(XmlEvent::start_element("body"))?;
+
+    w.write(XmlEvent::start_element("header").attr("class", "book"))?;
+    w.write(XmlEvent::start_element("h1"))?;
+    w.write(XmlEvent::characters(book.title))?;
+    w.write(XmlEvent::end_element())?; // </h1>
+    if !book.authors.is_empty() {
+        w.write(XmlEvent::start_element("p"))?;
+        w.write(XmlEvent::characters(&book.authors.join(", ")))?;
+        w.write(XmlEvent::end_element())?; // </p>
+    }
+    w.write(XmlEvent::end_element())?; // </header>
+
+    let toc = &body.config.toc;
+    if toc.enabled {
+        w.write(
+            XmlEvent::start_element("nav")
+                .attr("id", "toc")
+                .attr("class", "toc"),
+        )?;
+        w.write(XmlEvent::start_element("h2"))?;
+        w.write(XmlEvent::characters(&toc.title))?;
+        w.write(XmlEvent::end_element())?; // </h2>
+        let href = |chapter: &BookChapter| {
+            let source_path = chapter.dest_path.to_string_lossy().replace('\\', "/");
+            format!("#{}", xhtml::section_id(&source_path))
+        };
+        xhtml::write_nav_list(&book.tree, toc.depth, &href, w)?;
+        w.write(XmlEvent::end_element())?; // </nav>
+    }
+
+    w.write(XmlEvent::start_element("main"))?;
+    body.render_tree(&book.tree)?;
+    body.write_notes()?;
+    body.w.write(XmlEvent::end_element())?; // </main>
+
+    body.w.write(XmlEvent::end_element()) // </body>
+}
+
+/// Writes `book` as a single html document, with its stylesheets and the files referenced by its chapters (such as images) embedded in it.
+///
+/// Chapters are sections of the document, and links between them point to their sections.
+/// `lang` is the language of the document, written as the `lang` of its root element
+pub fn write_html<W: io::Write>(
+    mut writer: W,
+    book: Book,
+    lang: &str,
+    config: &HtmlConfig,
+) -> io::Result<()> {
+    let mut stylesheets = Vec::new();
+    if config.default_css {
+        stylesheets.push(Cow::Borrowed(DEFAULT_STYLE));
+        stylesheets.push(Cow::Borrowed(PAGE_STYLE));
+    }
+
+    // The theme is embedded before the book's own stylesheets, so that they can override it
+    #[cfg(feature = "highlight")]
+    let highlight_theme = config
+        .highlight_theme
+        .as_deref()
+        .map(crate::highlight::find_theme)
+        .transpose()?;
+    #[cfg(feature = "highlight")]
+    if let Some(theme) = highlight_theme {
+        stylesheets.push(Cow::Owned(crate::highlight::theme_css(theme)?));
+    }
+
+    for css in &config.css {
+        stylesheets.push(Cow::Owned(read_stylesheet(css, book.extra_files)?));
+    }
+
+    let mut embedded = HashMap::new();
+    for node in book.tree.nested() {
+        if let NavHeading::Chapter(_, chapter) = &node.heading {
+            for path in xhtml::chapter_resources(chapter) {
+                if embedded.contains_key(&path) {
+                    continue;
+                }
+                if let Some(file) = find_source_file(Path::new(&path), book.extra_files) {
+                    let url = data_url(file)?;
+                    embedded.insert(path, url);
+                }
+            }
+        }
+    }
+
+    let links = ChapterLinks::new(
+        book.tree.nested().filter_map(|node| match &node.heading {
+            NavHeading::Chapter(_, chapter) => Some(chapter),
+            _ => None,
+        }),
+        config.omitted_chapter_links.clone(),
+    );
+
+    writeln!(writer, "<!DOCTYPE html>")?;
+    if lang.is_empty() {
+        writeln!(writer, "<html>")?;
+    } else {
+        writeln!(writer, "<html lang=\"{}\">", escape_str_attribute(lang))?;
+    }
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    writeln!(
+        writer,
+        "<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">"
+    )?;
+    writeln!(writer, "<title>{}</title>", escape_str_pcdata(book.title))?;
+    for author in book.authors {
+        writeln!(
+            writer,
+            "<meta name=\"author\" content=\"{}\">",
+            escape_str_attribute(author)
+        )?;
+    }
+    // Stylesheets are written as they are, as the content of `<style>` is not escaped in html
+    for css in &stylesheets {
+        writeln!(writer, "<style>\n{}\n</style>", css.trim())?;
+    }
+    writeln!(writer, "</head>")?;
+
+    let mut xhtml = Vec::new();
+    {
+        let mut w = EmitterConfig::new()
+            .write_document_declaration(false)
+            .normalize_empty_elements(false)
+            .cdata_to_characters(true)
+            .create_writer(&mut xhtml);
+        let mut body = BodyWriter {
+            config,
+            links: &links,
+            embedded: &embedded,
+            #[cfg(feature = "highlight")]
+            highlight: highlight_theme.is_some(),
+            notes: Vec::new(),
+            w: &mut w,
+        };
+        write_body(&book, &mut body).map_err(xhtml::xml_to_io_error)?;
+    }
+    let xhtml =
+        String::from_utf8(xhtml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_all(html_syntax(&xhtml).as_bytes())?;
+    writeln!(writer)?;
+    writeln!(writer, "</html>")?;
+    writer.flush()
+}
@@ -0,0 +1,67 @@
+use std::{ops::Deref, path::PathBuf};
+
+use serde_derive::Deserialize;
+
+use crate::{
+    config::SharedConfig,
+    xhtml::{FootnotePlacement, OmittedChapterLinks},
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+#[non_exhaustive]
+pub struct HtmlConfig {
+    #[serde(flatten)]
+    pub shared: SharedConfig,
+    /// Where footnotes are written. With [`FootnotePlacement::EndOfBook`], they are collected into a section at the end of the document
+    pub footnotes: FootnotePlacement,
+    /// How links to chapters that are not included in an output are written
+    pub omitted_chapter_links: OmittedChapterLinks,
+    /// Stylesheets embedded in every output, relative to the book source directory
+    pub css: Vec<PathBuf>,
+    /// Whether the bundled default stylesheets are embedded (before any stylesheets in `css`)
+    pub default_css: bool,
+    pub toc: TocConfig,
+}
+
+impl Default for HtmlConfig {
+    fn default() -> Self {
+        Self {
+            shared: SharedConfig::default(),
+            footnotes: FootnotePlacement::default(),
+            omitted_chapter_links: OmittedChapterLinks::default(),
+            css: Vec::new(),
+            default_css: true,
+            toc: TocConfig::default(),
+        }
+    }
+}
+
+impl Deref for HtmlConfig {
+    type Target = SharedConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shared
+    }
+}
+
+/// The table of contents written at the start of each output, linking to the chapters in the document
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+#[non_exhaustive]
+pub struct TocConfig {
+    pub enabled: bool,
+    pub title: String,
+    /// The number of levels of the table of contents, or every level if `None`
+    pub depth: Option<usize>,
+}
+
+impl Default for TocConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            title: "Table of Contents".to_string(),
+            depth: None,
+        }
+    }
+}
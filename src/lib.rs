@@ -1,4 +1,7 @@
-use bookir::{nav::NavTree, Book, BookChapter, ExtraItem, RichTextParser};
+use bookir::{
+    nav::{NavNode, NavTree},
+    Book, ExtraItem, ParsedChapters,
+};
 use config::{Config, OutputFile, OutputType, SerList};
 use helpers::name_to_id;
 use mdbook::{book::Chapter, renderer::RenderContext, BookItem};
@@ -17,6 +20,10 @@ pub mod config;
 #[cfg(feature = "epub")]
 pub mod epub;
 pub mod helpers;
+#[cfg(feature = "highlight")]
+pub mod highlight;
+#[cfg(feature = "html")]
+pub mod html;
 
 #[cfg(feature = "pdf")]
 pub mod pdf;
@@ -31,6 +38,7 @@ pub mod bookir;
 pub enum Output {
     Full,
     Part(String),
+    Chapter(String),
     ByPartHead,
     ByPartTail,
 }
@@ -44,7 +52,8 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
     let config: C = ctx
         .config
         .get_deserialized_opt(format!("output.{output_name}"))
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        // The alternate form includes the cause, which names the setting that could not be deserialized
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:#}")))?
         .unwrap_or_default();
 
     let mut chapter_list = HashMap::new();
@@ -55,6 +64,10 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
         options.math = config.math_support;
     }
 
+    // Parse every chapter up front, so that all of the problems in the book are reported at once,
+    // rather than only those in the first output that fails
+    let parsed = ParsedChapters::parse(&ctx.book, options)?;
+
     let authors = ctx
         .config
         .book
@@ -98,10 +111,13 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
             Err(e) => Err(e),
         })
         .collect::<io::Result<Vec<_>>>()?;
+    // The order of directory entries depends on the filesystem, but outputs should not
+    extra_files.sort_by(|a, b| a.dest_path.cmp(&b.dest_path));
 
     for output in config.outputs() {
         match output {
-            OutputType::Chapter => todo!(),
+            // Chapter outputs are built directly from the book, as the always-included chapters depend on the position of each chapter
+            OutputType::Chapter => {}
             OutputType::Part => {
                 let mut always_include_head = Vec::new();
                 let mut always_include_tail = Vec::new();
@@ -117,19 +133,16 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
                                 chapter_list.insert(Output::Part(part), chapters);
                             }
                         }
-                        it @ BookItem::PartTitle(title) => {
+                        BookItem::PartTitle(title) => {
                             has_entries = true;
-                            let id = name_to_id(&title);
+                            let id = name_to_id(title);
                             let chapters = core::mem::take(&mut cur_part_chapters);
                             if let Some(part) = cur_part.take() {
                                 chapter_list.insert(Output::Part(part), chapters);
                             }
                             titles.insert(
                                 Output::Part(id.clone()),
-                                title
-                                    .split_once('{')
-                                    .map_or(&**title, |(l, r)| l)
-                                    .to_string(),
+                                helpers::display_title(title).to_string(),
                             );
                             cur_part = Some(id);
                         }
@@ -172,14 +185,9 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
                     ctx.config
                         .book
                         .title
-                        .clone()
-                        .map(|mut s| {
-                            if let Some(idx) = s.find('{') {
-                                s.truncate(idx);
-                            }
-                            s
-                        })
-                        .unwrap_or_else(|| "Epub Book".to_string()),
+                        .as_deref()
+                        .map_or("Epub Book", helpers::display_title)
+                        .to_string(),
                 );
             }
         }
@@ -187,17 +195,66 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
 
     for output in config.outputs() {
         match output {
-            OutputType::Chapter => todo!(),
+            OutputType::Chapter => {
+                let chapters = ctx
+                    .book
+                    .iter()
+                    .filter_map(|item| match item {
+                        BookItem::Chapter(ch) if ch.path.is_some() => Some(ch),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                let always_included = |ch: &&&Chapter| {
+                    ch.path.as_deref().is_some_and(|path| {
+                        config
+                            .always_include
+                            .contains(path.strip_prefix("..").unwrap_or(path))
+                    })
+                };
+
+                for (idx, ch) in chapters.iter().enumerate() {
+                    let id = helpers::chapter_output_id(ch);
+
+                    let title = helpers::display_title(&ch.name);
+                    let path = match config.output_files.individual_files.get(&id) {
+                        Some(OutputFile::Path(path)) => Path::new(path),
+                        None | Some(OutputFile::Enabled(true)) => Path::new(&id),
+                        Some(OutputFile::Enabled(false)) => continue,
+                    };
+
+                    let mut nav = NavTree::new();
+
+                    for head in chapters[..idx].iter().filter(always_included) {
+                        nav.push(NavNode::from_chapter(head, &parsed));
+                    }
+                    nav.push(NavNode::from_chapter(ch, &parsed));
+                    for tail in chapters[idx + 1..].iter().filter(always_included) {
+                        nav.push(NavNode::from_chapter(tail, &parsed));
+                    }
+
+                    let book = bookir::Book {
+                        title,
+                        tree: nav,
+                        extra_files: &extra_files,
+                        authors: &authors,
+                        id: &id,
+                        series: None,
+                    };
+
+                    visitor(path, &src, book, &config, &Output::Chapter(id.clone()))?;
+                }
+            }
             OutputType::Part => {
+                let series_title = ctx.config.book.title.as_deref().map(helpers::display_title);
+                let mut position = 0;
                 for c in ctx.book.iter() {
                     match c {
                         BookItem::PartTitle(title) => {
+                            position += 1;
                             let id = helpers::name_to_id(title);
 
-                            let title = title
-                                .split_once("{")
-                                .map_or(&**title, |(title, _)| title)
-                                .trim();
+                            let title = helpers::display_title(title);
                             let path = match config.output_files.individual_files.get(&id) {
                                 Some(OutputFile::Path(path)) => Path::new(path),
                                 None | Some(OutputFile::Enabled(true)) => Path::new(&id),
@@ -207,11 +264,11 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
                             let part = Output::Part(id.clone());
 
                             let mut nav =
-                                NavTree::from_items(&chapter_list[&Output::ByPartHead], options);
-                            nav.append_tree(NavTree::from_items(&chapter_list[&part], options));
+                                NavTree::from_items(&chapter_list[&Output::ByPartHead], &parsed);
+                            nav.append_tree(NavTree::from_items(&chapter_list[&part], &parsed));
                             nav.append_tree(NavTree::from_items(
                                 &chapter_list[&Output::ByPartTail],
-                                options,
+                                &parsed,
                             ));
 
                             let book = bookir::Book {
@@ -220,6 +277,8 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
                                 extra_files: &extra_files,
                                 authors: &authors,
                                 id: &id,
+                                series: series_title
+                                    .map(|title| bookir::Series { title, position }),
                             };
 
                             visitor(path, &src, book, &config, &part)?;
@@ -244,9 +303,9 @@ pub fn gen_collected_output<C: Config + for<'a> serde::Deserialize<'a>>(
                         .book
                         .title
                         .as_deref()
-                        .unwrap_or("Placeholder Title"),
+                        .map_or("Placeholder Title", helpers::display_title),
                     &chapter_list[&Output::Full],
-                    options,
+                    &parsed,
                     &extra_files,
                     &authors,
                     title_id.as_deref().unwrap_or("book"),
@@ -1,23 +1,174 @@
-use std::io;
+use std::{io, path::Path};
 
 use fonts::FontSet;
-use krilla::metadata::Metadata;
+use krilla::{
+    action::LinkAction,
+    annotation::{LinkAnnotation, Target},
+    color::rgb,
+    destination::XyzDestination,
+    geom::{Point, Size},
+    metadata::Metadata,
+    outline::Outline,
+    path::{Fill, PathBuilder, Stroke},
+    surface::TextDirection,
+    PageSettings,
+};
+use layout::{DrawOp, FontFamily, LinkTarget, Typesetter};
 use uuid::Uuid;
 
 use crate::bookir::Book;
 
 pub mod config;
+pub mod layout;
+
+fn font_family(set: &FontSet) -> io::Result<FontFamily> {
+    let load = |font: &fonts::ConstFont| {
+        font.font().cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Failed to load embedded font")
+        })
+    };
+
+    Ok(FontFamily {
+        regular: load(&set.base)?,
+        italics: load(&set.italics)?,
+        bold: load(&set.bold)?,
+        bold_italics: load(&set.bold_italics)?,
+    })
+}
+
+fn color((r, g, b): (u8, u8, u8)) -> rgb::Color {
+    rgb::Color::new(r, g, b)
+}
 
 pub fn write_pdf<W: std::io::Write>(
-    file: W,
+    mut file: W,
     book: Book,
+    src_dir: &Path,
     file_id: Uuid,
     def_font: FontSet,
     mono_font: FontSet,
+    config: &config::PdfConfig,
 ) -> io::Result<()> {
+    let typesetter = Typesetter::new(
+        config.page,
+        font_family(&def_font)?,
+        font_family(&mono_font)?,
+        src_dir,
+    );
+    #[cfg(feature = "highlight")]
+    let typesetter = match &config.highlight_theme {
+        Some(theme) => typesetter.with_highlight(crate::highlight::find_theme(theme)?),
+        None => typesetter,
+    };
+    let (width, height) = typesetter.page_size();
+    let (pages, outline_nodes, anchors) = typesetter.layout(&book);
+
     let mut pdf = krilla::Document::new();
-    pdf.set_metadata(Metadata::new());
-    todo!()
+    pdf.set_metadata(
+        Metadata::new()
+            .title(book.title.to_string())
+            .authors(book.authors.iter().map(|a| a.to_string()).collect())
+            .document_id(file_id.to_string())
+            .creator("mdbook-fiction-tools".to_string()),
+    );
+
+    let mut outline = Outline::new();
+    for node in outline_nodes {
+        outline.push_child(node);
+    }
+    pdf.set_outline(outline);
+
+    for content in pages {
+        let mut page = pdf.start_page_with(PageSettings::new(width, height));
+        let mut surface = page.surface();
+
+        for op in content.ops {
+            match op {
+                DrawOp::Text {
+                    origin,
+                    font,
+                    size,
+                    text,
+                    color: c,
+                } => surface.fill_text(
+                    origin,
+                    Fill {
+                        paint: color(c).into(),
+                        ..Fill::default()
+                    },
+                    font,
+                    size,
+                    &[],
+                    &text,
+                    false,
+                    TextDirection::Auto,
+                ),
+                DrawOp::FillRect { rect, color: c } => surface.fill_path(
+                    &PathBuilder::from_rect(rect),
+                    Fill {
+                        paint: color(c).into(),
+                        ..Fill::default()
+                    },
+                ),
+                DrawOp::Line {
+                    from,
+                    to,
+                    width,
+                    color: c,
+                } => {
+                    let mut path = PathBuilder::new();
+                    path.move_to(from.x, from.y);
+                    path.line_to(to.x, to.y);
+                    if let Some(path) = path.finish() {
+                        surface.stroke_path(
+                            &path,
+                            Stroke {
+                                paint: color(c).into(),
+                                width,
+                                ..Stroke::default()
+                            },
+                        );
+                    }
+                }
+                DrawOp::Image { rect, image } => {
+                    surface.push_transform(&krilla::geom::Transform::from_translate(
+                        rect.x(),
+                        rect.y(),
+                    ));
+                    if let Some(size) = Size::from_wh(rect.width(), rect.height()) {
+                        surface.draw_image(image, size);
+                    }
+                    surface.pop();
+                }
+            }
+        }
+        surface.finish();
+
+        for link in content.links {
+            let target = match &link.target {
+                LinkTarget::Url(url) => Target::Action(LinkAction::new(url.clone()).into()),
+                target @ LinkTarget::Anchor(..) => match layout::resolve(&anchors, target) {
+                    Some((page, y)) => Target::Destination(
+                        XyzDestination::new(page, Point::from_xy(0.0, y)).into(),
+                    ),
+                    None => continue,
+                },
+            };
+            page.add_annotation(
+                LinkAnnotation {
+                    rect: link.rect,
+                    target,
+                }
+                .into(),
+            );
+        }
+        page.finish();
+    }
+
+    let bytes = pdf
+        .finish()
+        .map_err(|e| io::Error::other(format!("{e:?}")))?;
+    file.write_all(&bytes)
 }
 
-mod fonts;
+pub mod fonts;
@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::{io, ops::Deref};
 
 use serde::Deserialize;
 use uuid::Uuid;
@@ -11,6 +11,7 @@ pub struct PdfConfig {
     #[serde(flatten)]
     pub base: SharedConfig,
     pub file_ids: FileIds<Uuid>,
+    pub page: PageConfig,
 }
 
 impl Deref for PdfConfig {
@@ -19,3 +20,117 @@ impl Deref for PdfConfig {
         &self.base
     }
 }
+
+/// Standard paper sizes
+#[derive(Deserialize, Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaperSize {
+    A4,
+    A5,
+    Letter,
+    Legal,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum PageSize {
+    Paper(PaperSize),
+    /// A custom page size, in points
+    Custom {
+        width: f32,
+        height: f32,
+    },
+}
+
+impl PageSize {
+    /// The width and height of the page, in points
+    pub fn dimensions(&self) -> (f32, f32) {
+        match *self {
+            PageSize::Paper(PaperSize::A4) => (595.0, 842.0),
+            PageSize::Paper(PaperSize::A5) => (420.0, 595.0),
+            PageSize::Paper(PaperSize::Letter) => (612.0, 792.0),
+            PageSize::Paper(PaperSize::Legal) => (612.0, 1008.0),
+            PageSize::Custom { width, height } => (width, height),
+        }
+    }
+}
+
+/// The geometry of each page of the output. All lengths are in points.
+///
+/// The page size and font size must be positive, and the margin must leave room for content on the page
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(try_from = "RawPageConfig")]
+pub struct PageConfig {
+    pub size: PageSize,
+    pub margin: f32,
+    pub font_size: f32,
+}
+
+/// [`PageConfig`] as written in `book.toml`, before it is checked
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+struct RawPageConfig {
+    size: PageSize,
+    margin: f32,
+    font_size: f32,
+}
+
+impl Default for RawPageConfig {
+    fn default() -> Self {
+        let PageConfig {
+            size,
+            margin,
+            font_size,
+        } = PageConfig::default();
+        Self {
+            size,
+            margin,
+            font_size,
+        }
+    }
+}
+
+impl TryFrom<RawPageConfig> for PageConfig {
+    type Error = io::Error;
+
+    fn try_from(raw: RawPageConfig) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        // Also rejects NaN
+        let is_positive = |len: f32| len > 0.0;
+
+        let (width, height) = raw.size.dimensions();
+        if !is_positive(width) || !is_positive(height) {
+            return Err(invalid(format!(
+                "`page.size` must have a positive width and height, but is {width}x{height}"
+            )));
+        }
+        if !(0.0..width.min(height) / 2.0).contains(&raw.margin) {
+            return Err(invalid(format!(
+                "`page.margin` must be at least 0 and less than half of the width and height of the page, but is {}",
+                raw.margin
+            )));
+        }
+        if !is_positive(raw.font_size) {
+            return Err(invalid(format!(
+                "`page.font-size` must be positive, but is {}",
+                raw.font_size
+            )));
+        }
+
+        Ok(Self {
+            size: raw.size,
+            margin: raw.margin,
+            font_size: raw.font_size,
+        })
+    }
+}
+
+impl Default for PageConfig {
+    fn default() -> Self {
+        Self {
+            size: PageSize::Paper(PaperSize::Letter),
+            margin: 72.0,
+            font_size: 11.0,
+        }
+    }
+}
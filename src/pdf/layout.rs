@@ -0,0 +1,1433 @@
+//! Typesetting of a [`Book`] onto fixed-size pages.
+//!
+//! Layout is performed independently of the pdf document. The [`Typesetter`] produces a list of [`PageContent`]s,
+//! which contain positioned drawing operations that are replayed onto the pages of the document by [`super::write_pdf`].
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    path::{Path, PathBuf},
+};
+
+use krilla::{
+    destination::XyzDestination,
+    font::Font,
+    geom::{Point, Rect},
+    image::Image,
+    outline::OutlineNode,
+};
+use skrifa::{instance::Size, MetadataProvider};
+
+use crate::bookir::{
+    nav::{NavHeading, NavTree},
+    plain_text,
+    render::{self, Footnotes, LinkDestination, Renderer},
+    Alignment, Attributes, Book, BookChapter, BreakType, CodeBlock, FootnoteDefinition, Heading,
+    HeadingLevel, InlineXhtml, List, ListItem, ListStyle, RichText, Table, XmlNode,
+};
+
+use super::config::PageConfig;
+
+/// The height of a line, relative to the largest font size on that line
+const LINE_SPACING: f32 = 1.4;
+
+/// The position of the baseline within a line, relative to the height of the line
+const BASELINE: f32 = 0.72;
+
+/// The indentation of list items and block quotes, relative to the base font size
+const INDENT: f32 = 1.8;
+
+const TEXT_COLOR: (u8, u8, u8) = (0, 0, 0);
+const LINK_COLOR: (u8, u8, u8) = (0x1a, 0x3c, 0x8c);
+const RULE_COLOR: (u8, u8, u8) = (0x80, 0x80, 0x80);
+const CODE_BACKGROUND: (u8, u8, u8) = (0xf2, 0xf2, 0xf2);
+
+/// The font size of footnotes and footnote references, relative to the surrounding text
+const NOTE_SCALE: f32 = 0.8;
+
+/// The four styles of a font family, resolved from a [`super::fonts::FontSet`]
+#[derive(Clone, Debug)]
+pub struct FontFamily {
+    pub regular: Font,
+    pub italics: Font,
+    pub bold: Font,
+    pub bold_italics: Font,
+}
+
+impl FontFamily {
+    fn select(&self, bold: bool, italics: bool) -> &Font {
+        match (bold, italics) {
+            (false, false) => &self.regular,
+            (false, true) => &self.italics,
+            (true, false) => &self.bold,
+            (true, true) => &self.bold_italics,
+        }
+    }
+}
+
+/// A single drawing operation on a page. Coordinates are measured from the top-left corner of the page
+#[derive(Clone, Debug)]
+pub enum DrawOp {
+    Text {
+        origin: Point,
+        font: Font,
+        size: f32,
+        text: String,
+        color: (u8, u8, u8),
+    },
+    FillRect {
+        rect: Rect,
+        color: (u8, u8, u8),
+    },
+    Line {
+        from: Point,
+        to: Point,
+        width: f32,
+        color: (u8, u8, u8),
+    },
+    Image {
+        rect: Rect,
+        image: Image,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LinkTarget {
+    Url(String),
+    /// A location in the book, given by the destination path of a chapter and an optional element id in that chapter
+    Anchor(PathBuf, Option<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkArea {
+    pub rect: Rect,
+    pub target: LinkTarget,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PageContent {
+    pub ops: Vec<DrawOp>,
+    pub links: Vec<LinkArea>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct TextStyle {
+    mono: bool,
+    bold: bool,
+    italics: bool,
+    underline: bool,
+    strikethrough: bool,
+    superscript: bool,
+    size: f32,
+    color: (u8, u8, u8),
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Word(String, TextStyle, Option<LinkTarget>),
+    Space(TextStyle, Option<LinkTarget>),
+    Break,
+    Image(PathBuf, String, Option<LinkTarget>),
+    Anchor(String),
+    /// A reference to the footnote with the given label, which is placed at the bottom of the page the reference ends up on
+    Note(String),
+}
+
+#[derive(Clone, Debug)]
+struct Frag {
+    x: f32,
+    width: f32,
+    text: String,
+    style: TextStyle,
+    link: Option<LinkTarget>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Line {
+    frags: Vec<Frag>,
+    width: f32,
+    height: f32,
+    anchors: Vec<String>,
+    notes: Vec<String>,
+}
+
+impl Line {
+    fn push(&mut self, text: &str, width: f32, style: TextStyle, link: &Option<LinkTarget>) {
+        match self.frags.last_mut() {
+            Some(frag) if frag.style == style && frag.link == *link => {
+                frag.text.push_str(text);
+                frag.width += width;
+            }
+            _ => self.frags.push(Frag {
+                x: self.width,
+                width,
+                text: text.to_string(),
+                style,
+                link: link.clone(),
+            }),
+        }
+        self.width += width;
+        self.height = self.height.max(style.size * LINE_SPACING);
+    }
+}
+
+/// A position in the laid out document
+#[derive(Copy, Clone, Debug)]
+struct Mark {
+    page: usize,
+    y: f32,
+    op: usize,
+}
+
+pub struct Typesetter<'a> {
+    config: PageConfig,
+    width: f32,
+    height: f32,
+    fonts: FontFamily,
+    mono: FontFamily,
+    src_dir: &'a Path,
+    pages: Vec<PageContent>,
+    left: f32,
+    right: f32,
+    y: f32,
+    chapter: PathBuf,
+    anchors: Anchors,
+    /// The number and content of each footnote of the current chapter, keyed by label
+    notes: HashMap<String, (usize, Vec<Piece>)>,
+    /// The footnotes of the current chapter that have already been placed on a page
+    placed_notes: HashSet<String>,
+    /// The footnote lines waiting to be placed at the bottom of the current page
+    page_notes: Vec<Line>,
+    /// The height reserved at the bottom of the current page for `page_notes`
+    notes_height: f32,
+    /// The top of the footnote area of each page that has footnotes
+    note_tops: HashMap<usize, f32>,
+    /// The destination of each chapter and part, in the order they are laid out
+    destinations: Vec<XyzDestination>,
+    /// The theme that code blocks are highlighted with
+    #[cfg(feature = "highlight")]
+    highlight: Option<&'static syntect::highlighting::Theme>,
+}
+
+impl<'a> Typesetter<'a> {
+    pub fn new(config: PageConfig, fonts: FontFamily, mono: FontFamily, src_dir: &'a Path) -> Self {
+        let (width, height) = config.size.dimensions();
+        Self {
+            config,
+            width,
+            height,
+            fonts,
+            mono,
+            src_dir,
+            pages: Vec::new(),
+            left: config.margin,
+            right: width - config.margin,
+            y: config.margin,
+            chapter: PathBuf::new(),
+            anchors: HashMap::new(),
+            notes: HashMap::new(),
+            placed_notes: HashSet::new(),
+            page_notes: Vec::new(),
+            notes_height: 0.0,
+            note_tops: HashMap::new(),
+            destinations: Vec::new(),
+            #[cfg(feature = "highlight")]
+            highlight: None,
+        }
+    }
+
+    /// Highlights code blocks with `theme`
+    #[cfg(feature = "highlight")]
+    pub fn with_highlight(mut self, theme: &'static syntect::highlighting::Theme) -> Self {
+        self.highlight = Some(theme);
+        self
+    }
+
+    /// The lines of a code block, as words separated by line breaks. Highlighted code is split into runs in the colors of the theme
+    fn code_pieces(&self, code: &CodeBlock, style: TextStyle) -> Vec<Piece> {
+        let content = code.content.trim_end_matches('\n');
+        let mut pieces = Vec::new();
+
+        #[cfg(feature = "highlight")]
+        if let Some(lines) = self
+            .highlight
+            .and_then(|theme| crate::highlight::styled_lines(content, code.language(), theme))
+        {
+            use syntect::highlighting::FontStyle;
+
+            for line in lines {
+                for (run, text) in line {
+                    let mut style = style;
+                    style.color = (run.foreground.r, run.foreground.g, run.foreground.b);
+                    style.bold = run.font_style.contains(FontStyle::BOLD);
+                    style.italics = run.font_style.contains(FontStyle::ITALIC);
+                    style.underline = run.font_style.contains(FontStyle::UNDERLINE);
+                    pieces.push(Piece::Word(text.replace('\t', "    "), style, None));
+                }
+                pieces.push(Piece::Break);
+            }
+            return pieces;
+        }
+
+        for line in content.split('\n') {
+            let line = line.replace('\t', "    ");
+            if !line.is_empty() {
+                pieces.push(Piece::Word(line, style, None));
+            }
+            pieces.push(Piece::Break);
+        }
+        pieces
+    }
+
+    /// The background of code blocks, which is the background of the highlighting theme if it has one
+    fn code_background(&self) -> (u8, u8, u8) {
+        #[cfg(feature = "highlight")]
+        if let Some(color) = self.highlight.and_then(|theme| theme.settings.background) {
+            return (color.r, color.g, color.b);
+        }
+        CODE_BACKGROUND
+    }
+
+    /// The size of each page, in points
+    pub fn page_size(&self) -> (f32, f32) {
+        (self.width, self.height)
+    }
+
+    fn top(&self) -> f32 {
+        self.config.margin
+    }
+
+    fn bottom(&self) -> f32 {
+        self.height - self.config.margin
+    }
+
+    fn base_style(&self) -> TextStyle {
+        TextStyle {
+            mono: false,
+            bold: false,
+            italics: false,
+            underline: false,
+            strikethrough: false,
+            superscript: false,
+            size: self.config.font_size,
+            color: TEXT_COLOR,
+        }
+    }
+
+    fn font(&self, style: &TextStyle) -> &Font {
+        let family = if style.mono { &self.mono } else { &self.fonts };
+        family.select(style.bold, style.italics)
+    }
+
+    fn measure(&self, style: &TextStyle, text: &str) -> f32 {
+        let font = self.font(style);
+        let font_ref = font.font_ref();
+        let charmap = font_ref.charmap();
+        let metrics = font_ref.glyph_metrics(Size::new(style.size), font.location_ref());
+
+        text.chars()
+            .map(|c| {
+                charmap
+                    .map(c)
+                    .and_then(|glyph| metrics.advance_width(glyph))
+                    .unwrap_or(style.size * 0.5)
+            })
+            .sum()
+    }
+
+    fn page(&mut self) -> &mut PageContent {
+        if self.pages.is_empty() {
+            self.pages.push(PageContent::default());
+        }
+        self.pages.last_mut().unwrap()
+    }
+
+    fn new_page(&mut self) {
+        self.place_notes();
+        self.pages.push(PageContent::default());
+        self.y = self.top();
+    }
+
+    /// Starts a new page, unless nothing has been placed on the current page yet
+    fn fresh_page(&mut self) {
+        if self.pages.is_empty() || self.y > self.top() {
+            self.new_page();
+        }
+    }
+
+    fn ensure_space(&mut self, height: f32) {
+        if self.pages.is_empty()
+            || (self.y + height > self.bottom() - self.notes_height && self.y > self.top())
+        {
+            self.new_page();
+        }
+    }
+
+    fn mark(&mut self) -> Mark {
+        let y = self.y;
+        let op = self.page().ops.len();
+        Mark {
+            page: self.pages.len() - 1,
+            y,
+            op,
+        }
+    }
+
+    fn destination(&mut self) -> XyzDestination {
+        let mark = self.mark();
+        XyzDestination::new(mark.page, Point::from_xy(0.0, mark.y))
+    }
+
+    fn add_anchor(&mut self, id: Option<String>) {
+        let mark = self.mark();
+        self.anchors
+            .entry((self.chapter.clone(), id))
+            .or_insert((mark.page, mark.y));
+    }
+
+    fn gap(&mut self, height: f32) {
+        if self.y > self.top() {
+            self.y += height;
+        }
+    }
+
+    /// Calls `f` with the vertical extent covered on each page between `start` and the current position
+    fn for_each_span(&mut self, start: Mark, mut f: impl FnMut(&mut PageContent, usize, f32, f32)) {
+        let end = self.pages.len() - 1;
+        let (top, y) = (self.top(), self.y);
+        for (page_idx, page) in self.pages.iter_mut().enumerate().skip(start.page) {
+            let bottom = self
+                .note_tops
+                .get(&page_idx)
+                .copied()
+                .unwrap_or(self.height - self.config.margin);
+            let (from, op) = if page_idx == start.page {
+                (start.y, start.op)
+            } else {
+                (top, 0)
+            };
+            let to = if page_idx == end { y } else { bottom };
+            if to > from {
+                f(page, op, from, to);
+            }
+        }
+    }
+
+    /// Lays out the entire book, including a title page.
+    ///
+    /// Returns the laid out pages, the document outline, and the position of every anchor that internal links may refer to
+    pub fn layout(mut self, book: &Book) -> (Vec<PageContent>, Vec<OutlineNode>, Anchors) {
+        self.layout_title_page(book);
+        let Ok(()) = self.render_tree(&book.tree);
+        self.place_notes();
+        self.number_pages();
+
+        let outline = outline(&book.tree, &mut self.destinations.drain(..));
+        (self.pages, outline, self.anchors)
+    }
+
+    fn layout_title_page(&mut self, book: &Book) {
+        self.new_page();
+        let mut style = self.base_style();
+        style.size *= 2.4;
+        style.bold = true;
+        self.y = self.height / 3.0;
+        self.place_pieces(words(book.title, style, None), Alignment::Center);
+
+        let mut style = self.base_style();
+        style.size *= 1.3;
+        self.y += style.size;
+        for author in book.authors {
+            self.place_pieces(words(author, style, None), Alignment::Center);
+        }
+    }
+
+    fn number_pages(&mut self) {
+        let mut style = self.base_style();
+        style.size *= 0.8;
+        let font = self.font(&style).clone();
+        let y = self.height - self.config.margin / 2.0;
+
+        // The title page is not numbered
+        for idx in 1..self.pages.len() {
+            let text = format!("{}", idx + 1);
+            let x = (self.width - self.measure(&style, &text)) / 2.0;
+            self.pages[idx].ops.push(DrawOp::Text {
+                origin: Point::from_xy(x, y),
+                font: font.clone(),
+                size: style.size,
+                text,
+                color: TEXT_COLOR,
+            });
+        }
+    }
+
+    fn layout_paragraph<'b>(&mut self, elems: impl IntoIterator<Item = &'b RichText<'b>>) {
+        let pieces = self.collect_pieces(elems, self.base_style());
+        self.place_pieces(pieces, Alignment::Left);
+        self.gap(self.config.font_size * 0.6);
+    }
+
+    fn layout_table(&mut self, table: &Table) {
+        let columns = table
+            .head
+            .iter()
+            .chain(&table.body)
+            .map(|row| row.elems.len())
+            .chain([table.align.len()])
+            .max()
+            .unwrap_or(0);
+        if columns == 0 {
+            return;
+        }
+
+        let padding = self.config.font_size * 0.4;
+        let column_width = (self.right - self.left) / columns as f32;
+        let rows = table
+            .head
+            .iter()
+            .map(|row| (row, true))
+            .chain(table.body.iter().map(|row| (row, false)));
+
+        self.gap(self.config.font_size * 0.4);
+
+        for (row, is_head) in rows {
+            let mut style = self.base_style();
+            style.bold = is_head;
+
+            let mut cells = row
+                .elems
+                .iter()
+                .map(|cell| {
+                    let pieces = self.collect_pieces(&cell.0, style);
+                    VecDeque::from(self.break_lines(pieces, column_width - 2.0 * padding))
+                })
+                .collect::<Vec<_>>();
+            let min_height = style.size * LINE_SPACING;
+
+            // Each pass places as much of the row as fits on the page, splitting it at the line breaks of its cells
+            loop {
+                let row_height = cells
+                    .iter()
+                    .map(|lines| lines.iter().map(|line| line.height).sum::<f32>())
+                    .fold(min_height, f32::max)
+                    + 2.0 * padding;
+                // A row that fits on a page is kept together, while a taller one starts wherever a line of it fits
+                if row_height <= self.bottom() - self.top() {
+                    self.ensure_space(row_height);
+                } else {
+                    self.ensure_space(min_height + 2.0 * padding);
+                }
+
+                let top = self.y;
+                let limit = self.bottom() - self.notes_height - padding;
+                let mut bottom = top + min_height + 2.0 * padding;
+                for (col, lines) in cells.iter_mut().enumerate() {
+                    let align = table.align.get(col).copied().unwrap_or(Alignment::None);
+                    let x = self.left + column_width * col as f32 + padding;
+                    let mut y = top + padding;
+                    // At least one line is placed on each page, so that a line taller than a page cannot stop the row from ending
+                    while lines
+                        .front()
+                        .is_some_and(|line| y + line.height <= limit || y == top + padding)
+                    {
+                        let Some(line) = lines.pop_front() else { break };
+                        let height = line.height;
+                        self.draw_line(line, x, column_width - 2.0 * padding, y, align);
+                        y += height;
+                    }
+                    bottom = bottom.max(y + padding);
+                }
+                let done = cells.iter().all(VecDeque::is_empty);
+
+                let (left, right) = (self.left, self.right);
+                let page = self.page();
+                for y in [top, bottom] {
+                    page.ops.push(DrawOp::Line {
+                        from: Point::from_xy(left, y),
+                        to: Point::from_xy(right, y),
+                        width: if is_head && done && y == bottom {
+                            1.0
+                        } else {
+                            0.5
+                        },
+                        color: RULE_COLOR,
+                    });
+                }
+                for col in 0..=columns {
+                    let x = left + column_width * col as f32;
+                    page.ops.push(DrawOp::Line {
+                        from: Point::from_xy(x, top),
+                        to: Point::from_xy(x, bottom),
+                        width: 0.5,
+                        color: RULE_COLOR,
+                    });
+                }
+
+                self.y = bottom;
+                if done {
+                    break;
+                }
+                self.new_page();
+            }
+        }
+
+        self.gap(self.config.font_size * 0.6);
+    }
+
+    /// The path of the current chapter, as it is given to [`render::resolve_link`]
+    fn source_path(&self) -> String {
+        self.chapter.to_string_lossy().replace('\\', "/")
+    }
+
+    fn link_target(&self, dest_url: &str) -> LinkTarget {
+        match render::resolve_link(&self.source_path(), dest_url) {
+            LinkDestination::Chapter { path, fragment } => {
+                LinkTarget::Anchor(PathBuf::from(path), fragment.map(str::to_string))
+            }
+            // Links written against the html renderer's output refer to the same chapter
+            LinkDestination::File { path, fragment } => {
+                let mut target = PathBuf::from(path);
+                if target.extension().is_some_and(|ext| ext == "html") {
+                    target.set_extension("md");
+                }
+                LinkTarget::Anchor(target, fragment.map(str::to_string))
+            }
+            LinkDestination::External(url) => LinkTarget::Url(url.to_string()),
+        }
+    }
+
+    /// Collects the inline content `elems` into pieces, in `style`
+    fn collect_pieces<'b>(
+        &self,
+        elems: impl IntoIterator<Item = &'b RichText<'b>>,
+        style: TextStyle,
+    ) -> Vec<Piece> {
+        let mut collector = PieceCollector {
+            typesetter: self,
+            style,
+            link: None,
+            pieces: Vec::new(),
+        };
+        for elem in elems {
+            let Ok(()) = collector.render(elem);
+        }
+        collector.pieces
+    }
+
+    fn break_lines(&self, pieces: Vec<Piece>, max_width: f32) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut line = Line::default();
+        let mut space = None::<(TextStyle, Option<LinkTarget>)>;
+
+        for piece in pieces {
+            match piece {
+                Piece::Word(word, style, link) => {
+                    let width = self.measure(&style, &word);
+                    let space_width = match &space {
+                        Some((style, _)) if !line.frags.is_empty() => self.measure(style, " "),
+                        _ => 0.0,
+                    };
+
+                    if !line.frags.is_empty() && line.width + space_width + width > max_width {
+                        lines.push(core::mem::take(&mut line));
+                        space = None;
+                    } else if let Some((style, link)) = space.take() {
+                        if !line.frags.is_empty() {
+                            line.push(" ", space_width, style, &link);
+                        }
+                    }
+
+                    if width <= max_width - line.width {
+                        line.push(&word, width, style, &link);
+                    } else {
+                        // The word is too long for a line on its own, so break it wherever it overflows
+                        let mut buf = [0; 4];
+                        for c in word.chars() {
+                            let c = &*c.encode_utf8(&mut buf);
+                            let width = self.measure(&style, c);
+                            if !line.frags.is_empty() && line.width + width > max_width {
+                                lines.push(core::mem::take(&mut line));
+                            }
+                            line.push(c, width, style, &link);
+                        }
+                    }
+                }
+                Piece::Space(style, link) => space = Some((style, link)),
+                Piece::Break => {
+                    if line.height == 0.0 {
+                        line.height = self.config.font_size * LINE_SPACING;
+                    }
+                    lines.push(core::mem::take(&mut line));
+                    space = None;
+                }
+                Piece::Anchor(id) => line.anchors.push(id),
+                Piece::Note(label) => line.notes.push(label),
+                // Images are placed by `place_pieces` and never reach line breaking
+                Piece::Image(..) => {}
+            }
+        }
+
+        if !line.frags.is_empty() || !line.anchors.is_empty() {
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    /// Breaks `pieces` into lines, and places them on the page, starting new pages as necessary
+    fn place_pieces(&mut self, pieces: Vec<Piece>, align: Alignment) {
+        let mut run = Vec::new();
+
+        for piece in pieces {
+            match piece {
+                Piece::Image(path, alt, link) => {
+                    self.place_run(core::mem::take(&mut run), align);
+                    self.place_image(&path, &alt, link, align);
+                }
+                piece => run.push(piece),
+            }
+        }
+
+        self.place_run(run, align);
+    }
+
+    fn place_run(&mut self, run: Vec<Piece>, align: Alignment) {
+        if run.is_empty() {
+            return;
+        }
+
+        let width = self.right - self.left;
+        for line in self.break_lines(run, width) {
+            let notes = self.note_lines(&line.notes);
+            let notes_height: f32 = notes.iter().map(|line| line.height).sum();
+            let separator = |this: &Self| {
+                if notes.is_empty() || !this.page_notes.is_empty() {
+                    0.0
+                } else {
+                    this.config.font_size
+                }
+            };
+            self.ensure_space(line.height + notes_height + separator(self));
+            self.notes_height += notes_height + separator(self);
+            self.page_notes.extend(notes);
+
+            let height = line.height;
+            self.draw_line(line, self.left, width, self.y, align);
+            self.y += height;
+        }
+    }
+
+    /// Breaks the footnotes referenced by a line into lines, skipping footnotes that have already been placed
+    fn note_lines(&mut self, labels: &[String]) -> Vec<Line> {
+        let width = self.width - 2.0 * self.config.margin;
+        let mut lines = Vec::new();
+        for label in labels {
+            if !self.placed_notes.insert(label.clone()) {
+                continue;
+            }
+            if let Some((_, pieces)) = self.notes.get(label) {
+                lines.extend(self.break_lines(pieces.clone(), width));
+            }
+        }
+        lines
+    }
+
+    /// Places the footnotes referenced from the current page at the bottom of the page, below a short rule
+    fn place_notes(&mut self) {
+        if self.page_notes.is_empty() {
+            return;
+        }
+
+        let (left, width) = (self.config.margin, self.width - 2.0 * self.config.margin);
+        let top = self.bottom() - self.notes_height;
+        let rule = top + self.config.font_size / 2.0;
+        self.page().ops.push(DrawOp::Line {
+            from: Point::from_xy(left, rule),
+            to: Point::from_xy(left + width / 3.0, rule),
+            width: 0.5,
+            color: RULE_COLOR,
+        });
+        self.note_tops.insert(self.pages.len() - 1, top);
+
+        let mut y = top + self.config.font_size;
+        for line in core::mem::take(&mut self.page_notes) {
+            let height = line.height;
+            self.draw_line(line, left, width, y, Alignment::Left);
+            y += height;
+        }
+        self.notes_height = 0.0;
+    }
+
+    fn place_image(&mut self, path: &Path, alt: &str, link: Option<LinkTarget>, align: Alignment) {
+        let image = std::fs::read(path).ok().and_then(|data| {
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("png") => Image::from_png(&data),
+                Some("jpg" | "jpeg") => Image::from_jpeg(&data),
+                Some("gif") => Image::from_gif(&data),
+                Some("webp") => Image::from_webp(&data),
+                _ => None,
+            }
+        });
+
+        let Some(image) = image else {
+            // Fall back to the alt text for images that cannot be embedded
+            let mut style = self.base_style();
+            style.italics = true;
+            self.place_run(words(alt, style, link), align);
+            return;
+        };
+
+        let size = image.size();
+        let max_width = self.right - self.left;
+        let max_height = self.bottom() - self.top();
+        let scale = (max_width / size.width())
+            .min(max_height / size.height())
+            .min(1.0);
+        let (width, height) = (size.width() * scale, size.height() * scale);
+
+        self.ensure_space(height);
+        let x = match align {
+            Alignment::Center => self.left + (max_width - width) / 2.0,
+            Alignment::Right => self.right - width,
+            Alignment::None | Alignment::Left => self.left,
+        };
+        let y = self.y;
+        if let Some(rect) = Rect::from_xywh(x, y, width, height) {
+            let page = self.page();
+            page.ops.push(DrawOp::Image { rect, image });
+            if let Some(target) = link {
+                page.links.push(LinkArea { rect, target });
+            }
+        }
+        self.y += height;
+    }
+
+    fn draw_line(&mut self, line: Line, x: f32, width: f32, y: f32, align: Alignment) {
+        let x = match align {
+            Alignment::Center => x + (width - line.width) / 2.0,
+            Alignment::Right => x + width - line.width,
+            Alignment::None | Alignment::Left => x,
+        };
+        let baseline = y + line.height * BASELINE;
+
+        for id in line.anchors {
+            self.anchors
+                .entry((self.chapter.clone(), Some(id)))
+                .or_insert((self.pages.len() - 1, y));
+        }
+
+        for frag in line.frags {
+            let font = self.font(&frag.style).clone();
+            let style = frag.style;
+            let start = x + frag.x;
+            let page = self.page();
+
+            if let Some(target) = frag.link {
+                if let Some(rect) = Rect::from_xywh(start, y, frag.width, line.height) {
+                    page.links.push(LinkArea { rect, target });
+                }
+            }
+
+            let decorations = [
+                (style.underline, baseline + style.size * 0.12),
+                (style.strikethrough, baseline - style.size * 0.28),
+            ];
+            for (_, y) in decorations.into_iter().filter(|(enabled, _)| *enabled) {
+                page.ops.push(DrawOp::Line {
+                    from: Point::from_xy(start, y),
+                    to: Point::from_xy(start + frag.width, y),
+                    width: style.size / 16.0,
+                    color: style.color,
+                });
+            }
+
+            let baseline = if style.superscript {
+                baseline - style.size * 0.4
+            } else {
+                baseline
+            };
+            page.ops.push(DrawOp::Text {
+                origin: Point::from_xy(start, baseline),
+                font,
+                size: style.size,
+                text: frag.text,
+                color: style.color,
+            });
+        }
+    }
+}
+
+/// Lays out the blocks of the book onto pages. Runs of inline content are laid out as paragraphs, with their pieces collected by a [`PieceCollector`]
+impl<'a> Renderer<'a> for Typesetter<'_> {
+    type Error = Infallible;
+
+    fn render_all(&mut self, elems: &[RichText<'a>]) -> Result<(), Infallible> {
+        let mut inline = Vec::new();
+
+        for elem in elems {
+            if is_inline(elem) {
+                inline.push(elem);
+            } else {
+                if !inline.is_empty() {
+                    self.layout_paragraph(core::mem::take(&mut inline));
+                }
+                self.render(elem)?;
+            }
+        }
+
+        if !inline.is_empty() {
+            self.layout_paragraph(inline);
+        }
+        Ok(())
+    }
+
+    fn start_chapter(&mut self, _: &str, chapter: &'a BookChapter<'a>) -> Result<(), Infallible> {
+        self.fresh_page();
+        let dest = self.destination();
+        self.destinations.push(dest);
+        self.chapter = chapter.dest_path.to_path_buf();
+        self.add_anchor(None);
+
+        let notes = Footnotes::new(chapter);
+        self.placed_notes.clear();
+        self.notes = notes
+            .iter()
+            .enumerate()
+            .map(|(idx, def)| (def.label.to_string(), (idx + 1, Vec::new())))
+            .collect();
+
+        let mut style = self.base_style();
+        style.size *= NOTE_SCALE;
+        let contents = notes
+            .iter()
+            .enumerate()
+            .map(|(idx, def)| {
+                let mut collector = PieceCollector {
+                    typesetter: self,
+                    style,
+                    link: None,
+                    pieces: Vec::new(),
+                };
+                let Ok(()) = collector.footnote(idx + 1, def);
+                let mut pieces = collector.pieces;
+                // References from one footnote to another are not placed on their own
+                pieces.retain(|piece| !matches!(piece, Piece::Note(_)));
+                (def.label.to_string(), pieces)
+            })
+            .collect::<Vec<_>>();
+        for (label, pieces) in contents {
+            if let Some((_, content)) = self.notes.get_mut(&label) {
+                *content = pieces;
+            }
+        }
+        Ok(())
+    }
+
+    fn start_part(&mut self, title: &str) -> Result<(), Infallible> {
+        self.fresh_page();
+        let dest = self.destination();
+        self.destinations.push(dest);
+        let mut style = self.base_style();
+        style.size *= 2.0;
+        style.bold = true;
+        self.y = self.height / 3.0;
+        self.place_pieces(words(title, style, None), Alignment::Center);
+        Ok(())
+    }
+
+    /// Text is only rendered here outside of a block, as inline content is collected into paragraphs by [`Renderer::render_all`]
+    fn text(&mut self, text: &str) -> Result<(), Infallible> {
+        self.place_pieces(words(text, self.base_style(), None), Alignment::Left);
+        Ok(())
+    }
+
+    fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Infallible> {
+        let (XmlNode::Block(elem, _) | XmlNode::Inline(elem)) = node;
+        if let Some(id) = elem.attrs.get("id") {
+            self.add_anchor(Some(id.clone()));
+        }
+        match node {
+            XmlNode::Block(_, content) => self.render_all(content),
+            XmlNode::Inline(_) => Ok(()),
+        }
+    }
+
+    fn paragraph(&mut self, elems: &[RichText<'a>]) -> Result<(), Infallible> {
+        self.layout_paragraph(elems);
+        Ok(())
+    }
+
+    fn heading(&mut self, heading: &Heading<'a>) -> Result<(), Infallible> {
+        let scale = match heading.level {
+            HeadingLevel::H1 => 2.0,
+            HeadingLevel::H2 => 1.6,
+            HeadingLevel::H3 => 1.35,
+            HeadingLevel::H4 => 1.2,
+            HeadingLevel::H5 => 1.1,
+            HeadingLevel::H6 => 1.0,
+        };
+        let mut style = self.base_style();
+        style.size *= scale;
+        style.bold = true;
+
+        self.gap(style.size * 0.6);
+        // Keep the heading together with at least one line of the text that follows it
+        self.ensure_space(style.size * LINE_SPACING + self.config.font_size * LINE_SPACING);
+        self.add_anchor(Some(heading.id.to_string()));
+        let pieces = self.collect_pieces(&heading.elems, style);
+        self.place_pieces(pieces, Alignment::Left);
+        self.gap(style.size * 0.4);
+        Ok(())
+    }
+
+    fn block_quote(&mut self, elems: &[RichText<'a>]) -> Result<(), Infallible> {
+        let indent = self.config.font_size * INDENT;
+        let start = self.mark();
+        let bar = self.left + indent / 3.0;
+        self.left += indent;
+        self.render_all(elems)?;
+        self.left -= indent;
+        self.for_each_span(start, |page, _, from, to| {
+            page.ops.push(DrawOp::Line {
+                from: Point::from_xy(bar, from),
+                to: Point::from_xy(bar, to),
+                width: 2.0,
+                color: RULE_COLOR,
+            })
+        });
+        Ok(())
+    }
+
+    fn code_block(&mut self, code: &CodeBlock<'a>) -> Result<(), Infallible> {
+        let mut style = self.base_style();
+        style.mono = true;
+        style.size *= 0.85;
+        let padding = style.size * 0.5;
+
+        self.gap(padding);
+        let start = self.mark();
+        self.y += padding;
+
+        let pieces = self.code_pieces(code, style);
+
+        self.left += padding;
+        self.right -= padding;
+        self.place_pieces(pieces, Alignment::Left);
+        self.left -= padding;
+        self.right += padding;
+        self.y += padding;
+
+        let (left, right) = (self.left, self.right);
+        let color = self.code_background();
+        self.for_each_span(start, |page, op, from, to| {
+            if let Some(rect) = Rect::from_xywh(left, from, right - left, to - from) {
+                page.ops.insert(op, DrawOp::FillRect { rect, color });
+            }
+        });
+        self.gap(self.config.font_size * 0.6);
+        Ok(())
+    }
+
+    fn list(&mut self, list: &List<'a>) -> Result<(), Infallible> {
+        let indent = self.config.font_size * INDENT;
+        let style = self.base_style();
+
+        for (idx, item) in list.elems.iter().enumerate() {
+            let marker = match list.list_style {
+                ListStyle::Unordered => "\u{2022}".to_string(),
+                ListStyle::Ordered(start) => format!("{}.", start + idx as u64),
+            };
+
+            let line_height = style.size * LINE_SPACING;
+            self.ensure_space(line_height);
+            let marker_width = self.measure(&style, &marker);
+            let origin = Point::from_xy(
+                self.left + indent - marker_width - style.size * 0.4,
+                self.y + line_height * BASELINE,
+            );
+            let font = self.font(&style).clone();
+            self.page().ops.push(DrawOp::Text {
+                origin,
+                font,
+                size: style.size,
+                text: marker,
+                color: TEXT_COLOR,
+            });
+
+            self.left += indent;
+            self.list_item(item)?;
+            self.left -= indent;
+        }
+        self.gap(self.config.font_size * 0.6);
+        Ok(())
+    }
+
+    /// Loose list items contain paragraphs, tight list items contain inline elements directly.
+    /// The spacing after the last paragraph is removed so that tight lists remain tight
+    fn list_item(&mut self, item: &ListItem<'a>) -> Result<(), Infallible> {
+        self.render_all(&item.0)?;
+        if let Some(RichText::Paragraph(_)) = item.0.last() {
+            self.y -= self.config.font_size * 0.6;
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, table: &Table<'a>) -> Result<(), Infallible> {
+        self.layout_table(table);
+        Ok(())
+    }
+
+    fn text_break(&mut self, kind: BreakType) -> Result<(), Infallible> {
+        if let BreakType::Rule = kind {
+            self.gap(self.config.font_size);
+            self.ensure_space(self.config.font_size);
+            let y = self.y;
+            let width = self.right - self.left;
+            let (from, to) = (self.left + width / 4.0, self.right - width / 4.0);
+            self.page().ops.push(DrawOp::Line {
+                from: Point::from_xy(from, y),
+                to: Point::from_xy(to, y),
+                width: 0.75,
+                color: RULE_COLOR,
+            });
+            self.y += self.config.font_size;
+        }
+        Ok(())
+    }
+}
+
+/// Collects inline content into the pieces that lines are broken from.
+/// Block elements nested inside of inline content (such as a table cell) are flattened
+struct PieceCollector<'t, 's> {
+    typesetter: &'t Typesetter<'s>,
+    style: TextStyle,
+    link: Option<LinkTarget>,
+    pieces: Vec<Piece>,
+}
+
+impl PieceCollector<'_, '_> {
+    fn words(&mut self, text: &str, style: TextStyle) {
+        self.pieces.extend(words(text, style, self.link.clone()));
+    }
+
+    /// Collects `elems` in `style`, linking to `link`
+    fn render_styled<'a>(
+        &mut self,
+        elems: &[RichText<'a>],
+        style: TextStyle,
+        link: Option<LinkTarget>,
+    ) -> Result<(), Infallible> {
+        let outer_style = core::mem::replace(&mut self.style, style);
+        let outer_link = core::mem::replace(&mut self.link, link);
+        self.render_all(elems)?;
+        self.style = outer_style;
+        self.link = outer_link;
+        Ok(())
+    }
+}
+
+impl<'a> Renderer<'a> for PieceCollector<'_, '_> {
+    type Error = Infallible;
+
+    fn text(&mut self, text: &str) -> Result<(), Infallible> {
+        self.words(text, self.style);
+        Ok(())
+    }
+
+    fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Infallible> {
+        match node {
+            XmlNode::Inline(elem) => {
+                if elem.name == "br" {
+                    self.pieces.push(Piece::Break);
+                }
+                if let Some(id) = elem.attrs.get("id") {
+                    self.pieces.push(Piece::Anchor(id.clone()));
+                }
+                Ok(())
+            }
+            XmlNode::Block(elem, content) => {
+                let mut style = self.style;
+                let mut link = self.link.clone();
+                match &*elem.name {
+                    "b" | "strong" => style.bold = true,
+                    "i" | "em" | "cite" => style.italics = true,
+                    "u" | "ins" => style.underline = true,
+                    "s" | "del" | "strike" => style.strikethrough = true,
+                    "code" | "kbd" | "samp" | "tt" => style.mono = true,
+                    "a" => {
+                        if let Some(href) = elem.attrs.get("href") {
+                            style.color = LINK_COLOR;
+                            link = Some(self.typesetter.link_target(href));
+                        }
+                    }
+                    _ => {}
+                }
+                if let Some(id) = elem.attrs.get("id") {
+                    self.pieces.push(Piece::Anchor(id.clone()));
+                }
+                self.render_styled(content, style, link)
+            }
+        }
+    }
+
+    fn stylised(&mut self, attrs: &Attributes, elems: &[RichText<'a>]) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.bold |= attrs.bold;
+        style.italics |= attrs.italics;
+        style.underline |= attrs.underline;
+        style.strikethrough |= attrs.strikethrough;
+        self.render_styled(elems, style, self.link.clone())
+    }
+
+    fn inline_code(&mut self, code: &str) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.mono = true;
+        self.words(code, style);
+        Ok(())
+    }
+
+    fn code_block(&mut self, code: &CodeBlock<'a>) -> Result<(), Infallible> {
+        self.inline_code(&code.content)
+    }
+
+    fn internal_link(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.color = LINK_COLOR;
+        let link = self.typesetter.link_target(dest_url);
+        self.render_styled(elems, style, Some(link))
+    }
+
+    fn external_link(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.color = LINK_COLOR;
+        let link = LinkTarget::Url(dest_url.to_string());
+        self.render_styled(elems, style, Some(link))
+    }
+
+    fn footnote_reference(&mut self, label: &str) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.size *= NOTE_SCALE;
+        style.superscript = true;
+        style.color = LINK_COLOR;
+        let number = match self.typesetter.notes.get(label) {
+            Some((number, _)) => number.to_string(),
+            None => label.to_string(),
+        };
+        let link = LinkTarget::Anchor(self.typesetter.chapter.clone(), Some(note_anchor(label)));
+        self.pieces.push(Piece::Word(number, style, Some(link)));
+        self.pieces.push(Piece::Note(label.to_string()));
+        Ok(())
+    }
+
+    fn internal_image(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Infallible> {
+        match render::resolve_link(&self.typesetter.source_path(), dest_url) {
+            LinkDestination::Chapter { path, .. } | LinkDestination::File { path, .. } => {
+                let path = self.typesetter.src_dir.join(path);
+                self.pieces
+                    .push(Piece::Image(path, plain_text(elems), self.link.clone()));
+                Ok(())
+            }
+            LinkDestination::External(_) => self.external_image(title, elems, dest_url),
+        }
+    }
+
+    fn external_image(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> Result<(), Infallible> {
+        let mut style = self.style;
+        style.italics = true;
+        style.color = LINK_COLOR;
+        let link = Some(LinkTarget::Url(dest_url.to_string()));
+        self.pieces.extend(words(&plain_text(elems), style, link));
+        Ok(())
+    }
+
+    fn text_break(&mut self, kind: BreakType) -> Result<(), Infallible> {
+        match kind {
+            BreakType::SoftLine => self
+                .pieces
+                .push(Piece::Space(self.style, self.link.clone())),
+            BreakType::HardLine | BreakType::Rule => self.pieces.push(Piece::Break),
+        }
+        Ok(())
+    }
+
+    fn list_item(&mut self, item: &ListItem<'a>) -> Result<(), Infallible> {
+        self.render_all(&item.0)?;
+        self.pieces.push(Piece::Break);
+        Ok(())
+    }
+
+    /// A table nested in another block, with each row on a line of its own and its cells separated by `|`
+    fn table(&mut self, table: &Table<'a>) -> Result<(), Infallible> {
+        let rows = table
+            .head
+            .iter()
+            .map(|row| (row, true))
+            .chain(table.body.iter().map(|row| (row, false)));
+        for (row, is_head) in rows {
+            self.pieces.push(Piece::Break);
+            let mut style = self.style;
+            style.bold |= is_head;
+            for (idx, cell) in row.elems.iter().enumerate() {
+                if idx > 0 {
+                    self.pieces.push(Piece::Space(self.style, None));
+                    self.pieces
+                        .push(Piece::Word("|".to_string(), self.style, None));
+                    self.pieces.push(Piece::Space(self.style, None));
+                }
+                self.render_styled(&cell.0, style, self.link.clone())?;
+            }
+        }
+        self.pieces.push(Piece::Break);
+        Ok(())
+    }
+
+    /// The number of the footnote, followed by its content with a break before each block
+    fn footnote(&mut self, number: usize, def: &FootnoteDefinition<'a>) -> Result<(), Infallible> {
+        self.pieces.push(Piece::Anchor(note_anchor(&def.label)));
+        self.pieces
+            .push(Piece::Word(format!("{number}."), self.style, None));
+        self.pieces.push(Piece::Space(self.style, None));
+        for (idx, elem) in def.content.iter().enumerate() {
+            if idx > 0 && !is_inline(elem) {
+                self.pieces.push(Piece::Break);
+            }
+            self.render(elem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the document outline of `tree`, taking the destination of each chapter and part from `destinations` in the order they were laid out
+fn outline(
+    tree: &NavTree,
+    destinations: &mut impl Iterator<Item = XyzDestination>,
+) -> Vec<OutlineNode> {
+    let mut nodes = Vec::new();
+
+    for node in tree {
+        let entry = match &node.heading {
+            NavHeading::Chapter(title, _) | NavHeading::Heading(title) => destinations
+                .next()
+                .map(|dest| OutlineNode::new(title.to_string(), dest)),
+            NavHeading::UnboundChapter(_) => None,
+        };
+
+        let children = match &node.children {
+            Some(children) => outline(children, destinations),
+            None => Vec::new(),
+        };
+
+        match entry {
+            Some(mut entry) => {
+                for child in children {
+                    entry.push_child(child);
+                }
+                nodes.push(entry);
+            }
+            None => nodes.extend(children),
+        }
+    }
+
+    nodes
+}
+
+/// The page index and vertical position of each anchor in the book, keyed by chapter and element id
+pub type Anchors = HashMap<(PathBuf, Option<String>), (usize, f32)>;
+
+/// Resolves an internal link target to a page index and vertical position.
+/// Links to an unknown element of a known chapter resolve to the start of that chapter
+pub fn resolve(anchors: &Anchors, target: &LinkTarget) -> Option<(usize, f32)> {
+    match target {
+        LinkTarget::Url(_) => None,
+        LinkTarget::Anchor(path, id) => anchors
+            .get(&(path.clone(), id.clone()))
+            .or_else(|| anchors.get(&(path.clone(), None)))
+            .copied(),
+    }
+}
+
+/// The id of the anchor at the start of the footnote with `label`
+fn note_anchor(label: &str) -> String {
+    format!("fn-{label}")
+}
+
+fn words(text: &str, style: TextStyle, link: Option<LinkTarget>) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let trimmed = rest.trim_start();
+        if trimmed.len() != rest.len() {
+            pieces.push(Piece::Space(style, link.clone()));
+        }
+        let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+        if end > 0 {
+            pieces.push(Piece::Word(trimmed[..end].to_string(), style, link.clone()));
+        }
+        rest = &trimmed[end..];
+    }
+
+    pieces
+}
+
+fn is_inline(elem: &RichText) -> bool {
+    match elem {
+        RichText::RawText(_)
+        | RichText::Stylised(..)
+        | RichText::InlineCode(_)
+        | RichText::InternalLink(_)
+        | RichText::ExternalLink(_)
+        | RichText::InternalImage(_)
+        | RichText::ExternalImage(_)
+        | RichText::TextBreak(BreakType::SoftLine | BreakType::HardLine) => true,
+        RichText::Xhtml(InlineXhtml::Node(XmlNode::Block(elem, _))) => !matches!(
+            &*elem.name,
+            "div"
+                | "p"
+                | "section"
+                | "article"
+                | "aside"
+                | "blockquote"
+                | "figure"
+                | "figcaption"
+                | "header"
+                | "footer"
+                | "nav"
+                | "pre"
+                | "ul"
+                | "ol"
+                | "li"
+                | "table"
+                | "h1"
+                | "h2"
+                | "h3"
+                | "h4"
+                | "h5"
+                | "h6"
+        ),
+        RichText::Xhtml(InlineXhtml::Node(XmlNode::Inline(elem))) => elem.name == "br",
+        RichText::Xhtml(InlineXhtml::CData(_)) => true,
+        _ => false,
+    }
+}
@@ -1,16 +1,27 @@
+use serde::Deserialize;
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
     io::{self, Cursor},
 };
 use xml::{
     name::{Name, OwnedName},
-    namespace::NS_NO_PREFIX,
-    writer::{EventWriter, XmlEvent},
+    namespace::{NS_NO_PREFIX, NS_XML_PREFIX},
+    writer::{events::StartElementBuilder, EventWriter, XmlEvent},
 };
 
-use crate::bookir::{
-    Alignment, Book, BookChapter, CowStr, HeadingLevel, InlineXhtml, Link, ListStyle, RichText,
-    XmlNode,
+use crate::{
+    bookir::{
+        nav::{NavHeading, NavTree},
+        plain_text,
+        render::{self, Footnotes, LinkDestination, Renderer},
+        xml::XmlElem,
+        Alignment, Attributes, Book, BookChapter, BreakType, CodeBlock, FootnoteDefinition,
+        Heading, HeadingLevel, Link, List, ListItem, ListStyle, RichText, Table, TableCell,
+        TableRow, XmlNode,
+    },
+    helpers::{self, name_to_id},
 };
 
 pub fn xml_to_io_error(e: xml::writer::Error) -> std::io::Error {
@@ -35,251 +46,965 @@ pub const NS_XHTML_URI: &str = "http://www.w3.org/1999/xhtml";
 
 pub const XHTML_MEDIA: &str = "application/xhtml+xml";
 
-pub fn write_rich_node<W: std::io::Write>(
-    node: &RichText,
-    writer: &mut EventWriter<W>,
-) -> xml::writer::Result<()> {
-    match node {
-        RichText::Table(tbl) => {
-            let style = tbl.align.iter().map(|al| match al {
-                Alignment::None => None,
-                Alignment::Left => Some("text-align: left;"),
-                Alignment::Right => Some("text-align: right;"),
-                Alignment::Center => Some("text-align: center;"),
-            });
-
-            writer.write(XmlEvent::start_element("table"))?;
-
-            if let Some(head) = &tbl.head {
-                writer.write(XmlEvent::start_element("tr"))?;
-                for (style, elem) in style.clone().zip(&head.elems) {
-                    let mut th = XmlEvent::start_element("th");
-                    if let Some(style) = style {
-                        th = th.attr("style", style);
-                    }
-                    writer.write(th);
-                    write_rich_node(elem, writer)?;
-                    writer.write(XmlEvent::end_element())?;
-                }
-                writer.write(XmlEvent::end_element())?;
+pub const NS_EPUB_PREFIX: &str = "epub";
+pub const NS_EPUB_URI: &str = "http://www.idpf.org/2007/ops";
+
+/// The default path of the document that footnotes are collected into with [`FootnotePlacement::EndOfBook`] (see [`DocumentContext::with_notes_document`])
+pub const NOTES_DOCUMENT: &str = "notes.xhtml";
+
+/// Where the definitions of footnotes are written
+#[derive(Deserialize, Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FootnotePlacement {
+    /// In a section at the end of the chapter that references them
+    #[default]
+    EndOfChapter,
+    /// In a single document at the end of the book, see [`NOTES_DOCUMENT`]
+    EndOfBook,
+    /// Where they are defined in the source, for reading systems that show footnotes as popups
+    Popup,
+}
+
+/// How links to chapters that are not part of the output (such as the chapters of other parts) are written
+#[derive(Deserialize, Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum OmittedChapterLinks {
+    /// The text of the link is kept, without linking anywhere
+    #[default]
+    Text,
+    /// The link is removed, including its text
+    Drop,
+    /// The link points to the chapter in a web version of the book (such as the output of the `html` backend) at `url`
+    #[serde(untagged)]
+    Url { url: String },
+}
+
+/// The chapters of an output, which links between chapters are resolved against
+#[derive(Clone, Debug, Default)]
+pub struct ChapterLinks {
+    /// The path of each chapter in the book source, with `/` separators
+    chapters: HashSet<String>,
+    omitted: OmittedChapterLinks,
+}
+
+impl ChapterLinks {
+    pub fn new<'a>(
+        chapters: impl IntoIterator<Item = &'a BookChapter<'a>>,
+        omitted: OmittedChapterLinks,
+    ) -> Self {
+        Self {
+            chapters: chapters
+                .into_iter()
+                .map(|chapter| chapter.dest_path.to_string_lossy().replace('\\', "/"))
+                .collect(),
+            omitted,
+        }
+    }
+}
+
+/// The result of resolving the destination of an internal link
+enum LinkTarget {
+    Href(String),
+    /// Only the text of the link is written
+    Text,
+    /// Nothing is written
+    Drop,
+}
+
+/// Makes the container path `target` relative to the document at `document`
+fn relative_href(document: &str, target: &str) -> String {
+    let mut from = document.split('/').collect::<Vec<_>>();
+    from.pop();
+    let mut to = target.split('/').collect::<Vec<_>>();
+    let file = to.pop().unwrap_or_default();
+
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut href = "../".repeat(from.len() - common);
+    for dir in &to[common..] {
+        href.push_str(dir);
+        href.push('/');
+    }
+    href.push_str(file);
+    href
+}
+
+fn join_fragment(href: &str, fragment: Option<&str>) -> String {
+    match fragment {
+        Some(fragment) => format!("{href}#{fragment}"),
+        None => href.to_string(),
+    }
+}
+
+/// Identifies the chapter at `source_path` (relative to the book source directory, with `/` separators) among the chapters of the book.
+/// Prefixes the ids of its footnotes in the notes document
+fn chapter_key(source_path: &str) -> String {
+    name_to_id(&helpers::with_extension(source_path, "").replace('/', " "))
+}
+
+/// The id of the section holding the chapter at `source_path` when every chapter is written to one document.
+/// The `chapter-` prefix keeps it distinct from the ids of parts and of the other sections of the document, such as the table of contents
+pub fn section_id(source_path: &str) -> String {
+    format!("chapter-{}", chapter_key(source_path))
+}
+
+/// The attributes of inline xhtml that refer to files, such as the `src` of an `<img>`
+const RESOURCE_ATTRS: &[&str] = &["src", "data", "poster", "href", "xlink:href"];
+
+/// The files referenced by the content of `chapter`, relative to the book source directory.
+/// Links to other chapters are not included
+pub fn chapter_resources(chapter: &BookChapter) -> Vec<String> {
+    struct Resources {
+        source_path: String,
+        paths: Vec<String>,
+    }
+
+    impl Resources {
+        fn add(&mut self, href: &str) {
+            if let LinkDestination::File { path, .. } =
+                render::resolve_link(&self.source_path, href)
+            {
+                self.paths.push(path);
             }
+        }
+    }
+
+    impl<'a> Renderer<'a> for Resources {
+        type Error = Infallible;
+
+        fn text(&mut self, _: &str) -> Result<(), Infallible> {
+            Ok(())
+        }
 
-            for row in &tbl.body {
-                writer.write(XmlEvent::start_element("tr"))?;
-                for (style, elem) in style.clone().zip(&row.elems) {
-                    let mut td = XmlEvent::start_element("td");
-                    if let Some(style) = style {
-                        td = td.attr("style", style);
-                    }
-                    writer.write(td);
-                    write_rich_node(elem, writer)?;
-                    writer.write(XmlEvent::end_element())?;
+        fn xhtml_node(&mut self, node: &XmlNode<'a>) -> Result<(), Infallible> {
+            let (XmlNode::Inline(el) | XmlNode::Block(el, _)) = node;
+            for (name, value) in &el.attrs {
+                if RESOURCE_ATTRS.contains(&name.as_str()) {
+                    self.add(value);
                 }
-                writer.write(XmlEvent::end_element())?;
             }
+            match node {
+                XmlNode::Block(_, content) => self.render_all(content),
+                XmlNode::Inline(_) => Ok(()),
+            }
+        }
+
+        fn internal_link(
+            &mut self,
+            _: &str,
+            elems: &[RichText<'a>],
+            dest_url: &str,
+        ) -> Result<(), Infallible> {
+            self.add(dest_url);
+            self.render_all(elems)
+        }
+
+        fn internal_image(
+            &mut self,
+            _: &str,
+            elems: &[RichText<'a>],
+            dest_url: &str,
+        ) -> Result<(), Infallible> {
+            self.add(dest_url);
+            self.render_all(elems)
+        }
+
+        fn footnote_definition(&mut self, def: &FootnoteDefinition<'a>) -> Result<(), Infallible> {
+            self.render_all(&def.content)
+        }
+    }
+
+    let mut resources = Resources {
+        source_path: chapter.dest_path.to_string_lossy().replace('\\', "/"),
+        paths: Vec::new(),
+    };
+    let Ok(()) = resources.render_all(&chapter.content);
+    resources.paths
+}
 
-            writer.write(XmlEvent::end_element())
+/// The structural role of a chapter in the book, written as the `epub:type` of the `<section>` wrapping its content
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum SectionType {
+    /// An unnumbered chapter before the first numbered chapter, such as a foreword
+    FrontMatter,
+    /// A numbered chapter with chapters nested under it in `SUMMARY.md`
+    Part,
+    Chapter,
+    /// An unnumbered chapter after the numbered chapters, such as an afterword
+    BackMatter,
+}
+
+impl SectionType {
+    pub fn epub_type(self) -> &'static str {
+        match self {
+            Self::FrontMatter => "frontmatter",
+            Self::Part => "part",
+            Self::Chapter => "chapter",
+            Self::BackMatter => "backmatter",
         }
-        RichText::RawText(cow_str) => writer.write(XmlEvent::characters(cow_str)),
-        RichText::Xhtml(inline_xhtml) => match inline_xhtml {
-            InlineXhtml::CData(cdata) => writer.write(XmlEvent::cdata(cdata)),
-            InlineXhtml::Comment(comment) => writer.write(XmlEvent::comment(comment)),
-            InlineXhtml::Node(XmlNode::Block(elem_event, content)) => {
-                writer.write(elem_event)?;
-                for elem in content {
-                    write_rich_node(elem, writer)?;
+    }
+}
+
+/// Information about the document being written that is not part of its content, such as the resources it links to
+#[derive(Clone, Debug, Default)]
+pub struct DocumentContext<'r> {
+    /// The root of the container, relative to the document. Either empty, or a sequence of `../`
+    pub root_href: String,
+    /// The stylesheets linked from the document, relative to the root of the container
+    pub stylesheets: Vec<String>,
+    /// The language of the document, written as the `lang` and `xml:lang` of its root element. Omitted if empty
+    pub lang: String,
+    pub footnotes: FootnotePlacement,
+    /// The footnote definitions of the chapter, in the order they are numbered
+    pub notes: Footnotes<'r>,
+    /// Prepended to the ids of footnotes and references, so that they remain unique when notes from several chapters share a document
+    pub id_prefix: String,
+    /// The document containing the footnotes, relative to the chapter. Empty if it is the chapter itself
+    pub notes_href: String,
+    /// The chapter, relative to the document containing the footnotes. Empty if it is the chapter itself
+    pub chapter_href: String,
+    /// The path of the chapter in the book source, with `/` separators. Relative links in the chapter are resolved against it
+    pub source_path: String,
+    /// The path of the document being written, relative to the root of the container
+    pub document_path: String,
+    /// The chapters of the output. If `None`, links to chapters are written as if every chapter is part of the output
+    pub links: Option<&'r ChapterLinks>,
+    /// Whether code blocks are highlighted, as by [`crate::highlight::classed_tokens`]
+    #[cfg(feature = "highlight")]
+    pub highlight: bool,
+    /// Whether every chapter of the output is written to one document, each in a section with the id [`section_id`].
+    /// Links to chapters point to their sections, and the ids of headings, inline xhtml and footnotes are prefixed by `id_prefix`.
+    /// Structural semantics are written as ARIA roles rather than `epub:type`s
+    pub single_document: bool,
+    /// Files of the book source that are embedded in the document, mapped from their path (with `/` separators) to the `data:` url written in place of links to them
+    pub embedded: Option<&'r HashMap<String, String>>,
+}
+
+impl<'r> DocumentContext<'r> {
+    pub fn for_chapter(chapter: &'r BookChapter<'r>, footnotes: FootnotePlacement) -> Self {
+        let notes = Footnotes::new(chapter);
+        let depth = chapter.dest_path.components().count() - 1;
+        let source_path = chapter.dest_path.to_string_lossy().replace('\\', "/");
+        let mut cx = Self {
+            root_href: "../".repeat(depth),
+            footnotes,
+            notes,
+            document_path: helpers::with_extension(&source_path, "xhtml"),
+            source_path,
+            ..Default::default()
+        };
+
+        if footnotes == FootnotePlacement::EndOfBook {
+            cx.id_prefix = chapter_key(&cx.source_path) + "-";
+            cx.chapter_href = cx.document_path.clone();
+            cx.notes_href = cx.root_href.clone() + NOTES_DOCUMENT;
+        }
+
+        cx
+    }
+
+    /// Writes the chapter into a document holding every chapter of the output (see [`DocumentContext::single_document`]).
+    ///
+    /// Footnotes link to each other within the document, wherever they are placed
+    pub fn in_single_document(mut self) -> Self {
+        self.single_document = true;
+        self.id_prefix = section_id(&self.source_path) + "-";
+        self.chapter_href = String::new();
+        self.notes_href = String::new();
+        self
+    }
+
+    pub fn with_embedded(mut self, embedded: &'r HashMap<String, String>) -> Self {
+        self.embedded = Some(embedded);
+        self
+    }
+
+    /// The number shown for the footnote with `label`
+    pub fn note_number(&self, label: &str) -> Option<usize> {
+        self.notes.number(label)
+    }
+
+    pub fn with_stylesheets(mut self, stylesheets: &[String]) -> Self {
+        self.stylesheets = stylesheets.to_vec();
+        self
+    }
+
+    pub fn with_lang(mut self, lang: &str) -> Self {
+        self.lang = lang.to_string();
+        self
+    }
+
+    pub fn with_links(mut self, links: &'r ChapterLinks) -> Self {
+        self.links = Some(links);
+        self
+    }
+
+    /// Links footnote references to the notes document at `path`, relative to the root of the container, instead of [`NOTES_DOCUMENT`]
+    pub fn with_notes_document(mut self, path: &str) -> Self {
+        if self.footnotes == FootnotePlacement::EndOfBook && !self.single_document {
+            self.notes_href = self.root_href.clone() + path;
+        }
+        self
+    }
+
+    /// Resolves the destination of an internal link or image in the chapter, relative to the document being written
+    fn resolve_link(&self, dest_url: &str) -> LinkTarget {
+        let (target, fragment) = match render::resolve_link(&self.source_path, dest_url) {
+            // Urls with a scheme (such as `mailto:`) and paths outside of the book source are not files of the book
+            LinkDestination::External(url) => return LinkTarget::Href(url.to_string()),
+            LinkDestination::Chapter { path, fragment } => {
+                if let Some(omitted) = self.omitted_link(&path, fragment) {
+                    return omitted;
+                }
+                if self.single_document {
+                    let section = section_id(&path);
+                    return LinkTarget::Href(match fragment {
+                        Some(fragment) => format!("#{section}-{fragment}"),
+                        None => format!("#{section}"),
+                    });
                 }
-                writer.write(XmlEvent::end_element())
+                (helpers::with_extension(&path, "xhtml"), fragment)
             }
-            InlineXhtml::Node(XmlNode::Inline(elem_event)) => {
-                writer.write(elem_event)?;
-                writer.write(XmlEvent::end_element())
+            LinkDestination::File { path, fragment } => {
+                if let Some(url) = self.embedded.and_then(|embedded| embedded.get(&path)) {
+                    return LinkTarget::Href(url.clone());
+                }
+                // A single document has no container, so files that are not embedded are linked to as they are written in the source
+                if self.single_document {
+                    return LinkTarget::Href(dest_url.to_string());
+                }
+                (path, fragment)
             }
-        },
-        RichText::Stylised(attributes, elems) => {
-            let mut steps = 0;
+        };
 
-            if attributes.strikethrough {
-                steps += 1;
-                writer.write(XmlEvent::start_element("s"))?;
-            }
-            if attributes.underline {
-                steps += 1;
-                writer.write(XmlEvent::start_element("u"))?;
+        if target == self.document_path && fragment.is_some() {
+            return LinkTarget::Href(join_fragment("", fragment));
+        }
+        LinkTarget::Href(join_fragment(
+            &relative_href(&self.document_path, &target),
+            fragment,
+        ))
+    }
+
+    /// How a link to the chapter at `resolved` is written, if the chapter is not part of the output
+    fn omitted_link(&self, resolved: &str, fragment: Option<&str>) -> Option<LinkTarget> {
+        let links = self
+            .links
+            .filter(|links| !links.chapters.contains(resolved))?;
+        Some(match &links.omitted {
+            OmittedChapterLinks::Text => LinkTarget::Text,
+            OmittedChapterLinks::Drop => LinkTarget::Drop,
+            OmittedChapterLinks::Url { url } => LinkTarget::Href(join_fragment(
+                &format!(
+                    "{}/{}",
+                    url.trim_end_matches('/'),
+                    helpers::with_extension(resolved, "html")
+                ),
+                fragment,
+            )),
+        })
+    }
+
+    /// The id written for the element with `id` in the source of the chapter
+    fn element_id<'i>(&self, id: &'i str) -> Cow<'i, str> {
+        if self.single_document {
+            Cow::Owned(format!("{}{id}", self.id_prefix))
+        } else {
+            Cow::Borrowed(id)
+        }
+    }
+
+    /// The attributes written for inline xhtml. The `id` is prefixed as by [`DocumentContext::element_id`],
+    /// and references to embedded files are replaced by their `data:` urls
+    fn xhtml_attrs<'e>(&self, elem: &'e XmlElem) -> Cow<'e, XmlElem> {
+        let mut elem = Cow::Borrowed(elem);
+        if let Some(id) = elem.attrs.get("id").filter(|_| self.single_document) {
+            let id = self.element_id(id).into_owned();
+            elem.to_mut().attrs.insert("id".to_string(), id);
+        }
+
+        let Some(embedded) = self.embedded else {
+            return elem;
+        };
+        for name in RESOURCE_ATTRS {
+            let url = elem
+                .attrs
+                .get(*name)
+                .and_then(|href| helpers::resolve_source_href(&self.source_path, href))
+                .and_then(|path| embedded.get(&path));
+            if let Some(url) = url {
+                let url = url.clone();
+                elem.to_mut().attrs.insert(name.to_string(), url);
             }
-            if attributes.bold {
-                steps += 1;
-                writer.write(XmlEvent::start_element("b"))?;
+        }
+        elem
+    }
+
+    /// Adds the structural semantics of an element to `start`: `epub_type` as its `epub:type`,
+    /// or `role` (the matching ARIA role) in a single document, which is html where `epub:type` has no meaning
+    fn semantics<'e>(
+        &self,
+        start: StartElementBuilder<'e>,
+        epub_type: &'e str,
+        role: &'e str,
+    ) -> StartElementBuilder<'e> {
+        if self.single_document {
+            start.attr("role", role)
+        } else {
+            start.attr(Name::prefixed("type", NS_EPUB_PREFIX), epub_type)
+        }
+    }
+
+    pub fn note_id(&self, label: &str) -> String {
+        format!("{}fn-{}", self.id_prefix, name_to_id(label))
+    }
+
+    pub fn noteref_id(&self, label: &str) -> String {
+        format!("{}fnref-{}", self.id_prefix, name_to_id(label))
+    }
+}
+
+/// Writes the `<aside>` for a single footnote.
+///
+/// Unless the footnote is written as a popup, the footnote number links back to the first reference to the footnote
+pub fn write_footnote<W: std::io::Write>(
+    def: &FootnoteDefinition,
+    cx: &mut DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let number = cx.note_number(&def.label).unwrap_or_default();
+    XhtmlRenderer { cx, writer }.footnote(number, def)
+}
+
+pub fn write_rich_node<W: std::io::Write>(
+    node: &RichText,
+    cx: &mut DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    XhtmlRenderer { cx, writer }.render(node)
+}
+
+/// Writes the content of a chapter as xhtml elements
+struct XhtmlRenderer<'c, 'r, 'w, W: std::io::Write> {
+    cx: &'c mut DocumentContext<'r>,
+    writer: &'w mut EventWriter<W>,
+}
+
+impl<W: std::io::Write> XhtmlRenderer<'_, '_, '_, W> {
+    /// Writes `elems` in the element started by `start`
+    fn element<'e>(
+        &mut self,
+        start: impl Into<XmlEvent<'e>>,
+        elems: &[RichText],
+    ) -> xml::writer::Result<()> {
+        self.writer.write(start)?;
+        self.render_all(elems)?;
+        self.writer.write(XmlEvent::end_element())
+    }
+
+    fn image(&mut self, src: &str, title: &str, elems: &[RichText]) -> xml::writer::Result<()> {
+        let alt = plain_text(elems);
+        let mut img = XmlEvent::start_element("img")
+            .attr("src", src)
+            .attr("alt", &alt);
+        if !title.is_empty() {
+            img = img.attr("title", title);
+        }
+        self.writer.write(img)?;
+        self.writer.write(XmlEvent::end_element()) // </img>
+    }
+}
+
+impl<'a, W: std::io::Write> Renderer<'a> for XhtmlRenderer<'_, '_, '_, W> {
+    type Error = xml::writer::Error;
+
+    fn text(&mut self, text: &str) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::characters(text))
+    }
+
+    fn xhtml_node(&mut self, node: &XmlNode<'a>) -> xml::writer::Result<()> {
+        match node {
+            XmlNode::Block(elem, content) => {
+                let elem = self.cx.xhtml_attrs(elem).into_owned();
+                self.element(&elem, content)
             }
-            if attributes.italics {
-                steps += 1;
-                writer.write(XmlEvent::start_element("i"))?;
+            XmlNode::Inline(elem) => {
+                self.writer.write(&*self.cx.xhtml_attrs(elem))?;
+                self.writer.write(XmlEvent::end_element())
             }
+        }
+    }
 
-            for elem in elems {
-                write_rich_node(elem, writer)?;
-            }
+    fn xhtml_comment(&mut self, comment: &str) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::comment(comment))
+    }
+
+    fn xhtml_cdata(&mut self, text: &str) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::cdata(text))
+    }
+
+    fn stylised(&mut self, attrs: &Attributes, elems: &[RichText<'a>]) -> xml::writer::Result<()> {
+        let mut steps = 0;
+
+        if attrs.strikethrough {
+            steps += 1;
+            self.writer.write(XmlEvent::start_element("s"))?;
+        }
+        if attrs.underline {
+            steps += 1;
+            self.writer.write(XmlEvent::start_element("u"))?;
+        }
+        if attrs.bold {
+            steps += 1;
+            self.writer.write(XmlEvent::start_element("b"))?;
+        }
+        if attrs.italics {
+            steps += 1;
+            self.writer.write(XmlEvent::start_element("i"))?;
+        }
+
+        self.render_all(elems)?;
 
-            for _ in 0..steps {
-                writer.write(XmlEvent::end_element())?;
+        for _ in 0..steps {
+            self.writer.write(XmlEvent::end_element())?;
+        }
+        Ok(())
+    }
+
+    fn paragraph(&mut self, elems: &[RichText<'a>]) -> xml::writer::Result<()> {
+        match elems {
+            // An image with a title on its own is a figure, captioned by the title
+            [image @ (RichText::InternalImage(Link::Text { title, .. })
+            | RichText::ExternalImage(Link::Text { title, .. }))]
+                if !title.is_empty() =>
+            {
+                self.writer.write(XmlEvent::start_element("figure"))?;
+                self.render(image)?;
+                self.writer.write(XmlEvent::start_element("figcaption"))?;
+                self.writer.write(XmlEvent::characters(title))?;
+                self.writer.write(XmlEvent::end_element())?; // </figcaption>
+                self.writer.write(XmlEvent::end_element()) // </figure>
             }
-            Ok(())
+            _ => self.element(XmlEvent::start_element("p"), elems),
         }
-        RichText::Paragraph(elems) => {
-            writer.write(XmlEvent::start_element("p"))?;
-            for elem in elems {
-                write_rich_node(elem, writer)?;
+    }
+
+    fn inline_code(&mut self, code: &str) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::start_element("code"))?;
+        self.writer.write(XmlEvent::characters(code))?;
+        self.writer.write(XmlEvent::end_element())
+    }
+
+    fn code_block(&mut self, code: &CodeBlock<'a>) -> xml::writer::Result<()> {
+        write_code_block(code, self.cx, self.writer)
+    }
+
+    fn block_quote(&mut self, elems: &[RichText<'a>]) -> xml::writer::Result<()> {
+        self.element(XmlEvent::start_element("blockquote"), elems)
+    }
+
+    fn internal_link(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> xml::writer::Result<()> {
+        match self.cx.resolve_link(dest_url) {
+            LinkTarget::Href(href) => {
+                self.element(XmlEvent::start_element("a").attr("href", &href), elems)
             }
-            writer.write(XmlEvent::end_element())
-        }
-        RichText::InlineCode(code) => {
-            writer.write(XmlEvent::start_element("code"))?;
-            writer.write(XmlEvent::cdata(code))?;
-            writer.write(XmlEvent::end_element())
-        }
-        RichText::CodeBlock(code) => {
-            writer.write(
-                XmlEvent::start_element("div")
-                    .attr("style", "font-family:monospace;background-color: #c9c9c9;"),
-            )?;
-            writer.write(XmlEvent::cdata(&code.content))?;
-            writer.write(XmlEvent::end_element())
-        }
-        RichText::InternalLink(link) => match link {
-            Link::Text {
-                title: _,
-                elems,
-                dest_url,
-            } => {
-                let link = if let Some(prefix) = dest_url.strip_suffix(".md") {
-                    CowStr::from(format!("{prefix}.xhtml"))
-                } else {
-                    dest_url.into()
-                };
+            LinkTarget::Text => self.render_all(elems),
+            LinkTarget::Drop => Ok(()),
+        }
+    }
 
-                writer.write(XmlEvent::start_element("a").attr("href", &link))?;
-                for elem in elems {
-                    write_rich_node(elem, writer)?;
-                }
-                writer.write(XmlEvent::end_element())
+    fn external_link(
+        &mut self,
+        _: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> xml::writer::Result<()> {
+        self.element(XmlEvent::start_element("a").attr("href", dest_url), elems)
+    }
+
+    fn footnote_reference(&mut self, label: &str) -> xml::writer::Result<()> {
+        let href = format!("{}#{}", self.cx.notes_href, self.cx.note_id(label));
+        let mut start = self
+            .cx
+            .semantics(XmlEvent::start_element("a"), "noteref", "doc-noteref")
+            .attr("href", &href);
+        // Only the first reference gets an id, which the footnote links back to
+        let id = self.cx.noteref_id(label);
+        if self.cx.notes.reference(label) {
+            start = start.attr("id", &id);
+        }
+        self.writer.write(start)?;
+        self.writer.write(XmlEvent::start_element("sup"))?;
+        match self.cx.note_number(label) {
+            Some(number) => self
+                .writer
+                .write(XmlEvent::characters(&format!("{number}")))?,
+            None => self.writer.write(XmlEvent::characters(label))?,
+        }
+        self.writer.write(XmlEvent::end_element())?; // </sup>
+        self.writer.write(XmlEvent::end_element()) // </a>
+    }
+
+    fn internal_image(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> xml::writer::Result<()> {
+        match self.cx.resolve_link(dest_url) {
+            LinkTarget::Href(href) => self.image(&href, title, elems),
+            LinkTarget::Text | LinkTarget::Drop => self.image(dest_url, title, elems),
+        }
+    }
+
+    fn external_image(
+        &mut self,
+        title: &str,
+        elems: &[RichText<'a>],
+        dest_url: &str,
+    ) -> xml::writer::Result<()> {
+        self.image(dest_url, title, elems)
+    }
+
+    fn heading(&mut self, heading: &Heading<'a>) -> xml::writer::Result<()> {
+        let start = match heading.level {
+            HeadingLevel::H1 => XmlEvent::start_element("h1"),
+            HeadingLevel::H2 => XmlEvent::start_element("h2"),
+            HeadingLevel::H3 => XmlEvent::start_element("h3"),
+            HeadingLevel::H4 => XmlEvent::start_element("h4"),
+            HeadingLevel::H5 => XmlEvent::start_element("h5"),
+            HeadingLevel::H6 => XmlEvent::start_element("h6"),
+        };
+
+        let id = self.cx.element_id(&heading.id).into_owned();
+        self.element(start.attr("id", &id), &heading.elems)
+    }
+
+    fn text_break(&mut self, kind: BreakType) -> xml::writer::Result<()> {
+        match kind {
+            BreakType::Rule => {
+                self.writer.write(XmlEvent::start_element("hr"))?;
+                self.writer.write(XmlEvent::end_element())
             }
-            Link::Footnote(id) => todo!(),
-        },
-        RichText::ExternalLink(link) => match link {
-            Link::Text {
-                title: _,
-                elems,
-                dest_url,
-            } => {
-                let link = dest_url.clone();
-
-                writer.write(XmlEvent::start_element("a").attr("href", &link))?;
-                for elem in elems {
-                    write_rich_node(elem, writer)?;
-                }
-                writer.write(XmlEvent::end_element())
+            // Soft breaks are only line breaks in the source, and reflow with the rest of the paragraph
+            BreakType::SoftLine => self.writer.write(XmlEvent::characters("\n")),
+            BreakType::HardLine => {
+                self.writer.write(XmlEvent::start_element("br"))?;
+                self.writer.write(XmlEvent::end_element())
             }
-            Link::Footnote(id) => unreachable!("External Link to a footnote not possible"),
-        },
-        RichText::InternalImage(link) | RichText::ExternalImage(link) => match link {
-            Link::Text {
-                title: _,
-                elems,
-                dest_url,
-            } => {
-                let link = dest_url.clone();
-
-                let mut alt = String::new();
-
-                for elem in elems {
-                    match elem {
-                        RichText::RawText(raw) => alt.push_str(raw),
-                        r => panic!("Can't include non-raw alt text in an image {r:?}"),
-                    }
-                }
+        }
+    }
+
+    fn list(&mut self, list: &List<'a>) -> xml::writer::Result<()> {
+        match list.list_style {
+            ListStyle::Ordered(n) => self
+                .writer
+                .write(XmlEvent::start_element("ol").attr("start", &format!("{n}")))?,
+            ListStyle::Unordered => self.writer.write(XmlEvent::start_element("ul"))?,
+        }
+
+        for item in &list.elems {
+            self.list_item(item)?;
+        }
+        self.writer.write(XmlEvent::end_element())
+    }
+
+    fn list_item(&mut self, item: &ListItem<'a>) -> xml::writer::Result<()> {
+        self.element(XmlEvent::start_element("li"), &item.0)
+    }
+
+    fn table(&mut self, table: &Table<'a>) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::start_element("table"))?;
+        if let Some(head) = &table.head {
+            self.writer.write(XmlEvent::start_element("thead"))?;
+            self.table_row(head, true, &table.align)?;
+            self.writer.write(XmlEvent::end_element())?; // </thead>
+        }
+        self.writer.write(XmlEvent::start_element("tbody"))?;
+        for row in &table.body {
+            self.table_row(row, false, &table.align)?;
+        }
+        self.writer.write(XmlEvent::end_element())?; // </tbody>
+        self.writer.write(XmlEvent::end_element()) // </table>
+    }
 
-                writer.write(
-                    XmlEvent::start_element("img")
-                        .attr("src", &link)
-                        .attr("alt", &alt),
-                )?;
-                for elem in elems {
-                    write_rich_node(elem, writer)?;
+    fn table_row(
+        &mut self,
+        row: &TableRow<'a>,
+        head: bool,
+        align: &[Alignment],
+    ) -> xml::writer::Result<()> {
+        self.writer.write(XmlEvent::start_element("tr"))?;
+        for (idx, cell) in row.elems.iter().enumerate() {
+            let align = align.get(idx).copied().unwrap_or(Alignment::None);
+            self.table_cell(cell, head, align)?;
+        }
+        self.writer.write(XmlEvent::end_element()) // </tr>
+    }
+
+    /// Writes a `th` or `td` element. Aligned columns are marked by a class, such as `align-center`
+    fn table_cell(
+        &mut self,
+        cell: &TableCell<'a>,
+        head: bool,
+        align: Alignment,
+    ) -> xml::writer::Result<()> {
+        let class = match align {
+            Alignment::None => None,
+            Alignment::Left => Some("align-left"),
+            Alignment::Center => Some("align-center"),
+            Alignment::Right => Some("align-right"),
+        };
+        let mut start = XmlEvent::start_element(if head { "th" } else { "td" });
+        if let Some(class) = class {
+            start = start.attr("class", class);
+        }
+        self.element(start, &cell.0)
+    }
+
+    fn footnote_definition(&mut self, def: &FootnoteDefinition<'a>) -> xml::writer::Result<()> {
+        match self.cx.footnotes {
+            FootnotePlacement::Popup => {
+                let number = self.cx.note_number(&def.label).unwrap_or_default();
+                self.footnote(number, def)
+            }
+            // Written after the content of the chapter, or into the notes document
+            FootnotePlacement::EndOfChapter | FootnotePlacement::EndOfBook => Ok(()),
+        }
+    }
+
+    fn footnote(&mut self, number: usize, def: &FootnoteDefinition<'a>) -> xml::writer::Result<()> {
+        let id = self.cx.note_id(&def.label);
+        // Popup footnotes stay where they are defined, others are collected at the end of the chapter or book
+        let role = match self.cx.footnotes {
+            FootnotePlacement::Popup => "doc-footnote",
+            FootnotePlacement::EndOfChapter | FootnotePlacement::EndOfBook => "doc-endnote",
+        };
+        self.writer.write(
+            self.cx
+                .semantics(XmlEvent::start_element("aside"), "footnote", role)
+                .attr("id", &id),
+        )?;
+
+        if self.cx.footnotes != FootnotePlacement::Popup {
+            let href = format!(
+                "{}#{}",
+                self.cx.chapter_href,
+                self.cx.noteref_id(&def.label)
+            );
+            self.writer
+                .write(XmlEvent::start_element("a").attr("href", &href))?;
+            self.writer
+                .write(XmlEvent::characters(&format!("{number}.")))?;
+            self.writer.write(XmlEvent::end_element())?; // </a>
+            self.writer.write(XmlEvent::characters(" "))?;
+        }
+
+        self.render_all(&def.content)?;
+        self.writer.write(XmlEvent::end_element()) // </aside>
+    }
+}
+
+/// Writes a code block as `<pre><code>`, marking the code with the language of the block
+fn write_code_block<W: std::io::Write>(
+    code: &CodeBlock,
+    #[cfg_attr(not(feature = "highlight"), allow(unused_variables))] cx: &DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let lang = code.language();
+    let class = format!("language-{lang}");
+    let mut code_start = XmlEvent::start_element("code");
+    if !lang.is_empty() {
+        code_start = code_start.attr("class", &class);
+    }
+
+    #[cfg(feature = "highlight")]
+    if let Some(tokens) = cx
+        .highlight
+        .then(|| crate::highlight::classed_tokens(&code.content, lang))
+        .flatten()
+    {
+        use crate::highlight::ClassedToken;
+
+        writer.write(XmlEvent::start_element("pre").attr("class", crate::highlight::CODE_CLASS))?;
+        writer.write(code_start)?;
+        for token in tokens {
+            match token {
+                ClassedToken::Start(classes) => {
+                    writer.write(XmlEvent::start_element("span").attr("class", &classes))?
                 }
-                writer.write(XmlEvent::end_element())
+                ClassedToken::End => writer.write(XmlEvent::end_element())?, // </span>
+                ClassedToken::Text(text) => writer.write(XmlEvent::characters(text))?,
             }
-            Link::Footnote(id) => unreachable!("External Link to a footnote not possible"),
-        },
-        RichText::Heading(heading) => {
-            let start = match heading.level {
-                HeadingLevel::H1 => XmlEvent::start_element("h1"),
-                HeadingLevel::H2 => XmlEvent::start_element("h2"),
-                HeadingLevel::H3 => XmlEvent::start_element("h3"),
-                HeadingLevel::H4 => XmlEvent::start_element("h4"),
-                HeadingLevel::H5 => XmlEvent::start_element("h5"),
-                HeadingLevel::H6 => XmlEvent::start_element("h6"),
-            };
-
-            writer.write(start.attr("id", &heading.id))?;
-            writer.write(XmlEvent::characters(&heading.text))?;
-            writer.write(XmlEvent::end_element())
-        }
-        RichText::TextBreak(break_type) => match break_type {
-            crate::bookir::BreakType::Rule => {
-                writer.write(XmlEvent::start_element("hr"))?;
-                writer.write(XmlEvent::end_element())
+        }
+        writer.write(XmlEvent::end_element())?; // </code>
+        return writer.write(XmlEvent::end_element()); // </pre>
+    }
+
+    writer.write(XmlEvent::start_element("pre"))?;
+    writer.write(code_start)?;
+    writer.write(XmlEvent::characters(&code.content))?;
+    writer.write(XmlEvent::end_element())?; // </code>
+    writer.write(XmlEvent::end_element()) // </pre>
+}
+
+/// Starts an XHTML document, up to and including the start of its `<html>` element
+pub fn write_document_start<W: std::io::Write>(
+    cx: &DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    writer.write(XmlEvent::StartDocument {
+        version: xml::common::XmlVersion::Version10,
+        encoding: Some("UTF-8"),
+        standalone: None,
+    })?;
+    let mut html = XmlEvent::start_element("html")
+        .ns(NS_NO_PREFIX, NS_XHTML_URI)
+        .ns(NS_EPUB_PREFIX, NS_EPUB_URI);
+    if !cx.lang.is_empty() {
+        html = html
+            .attr("lang", &cx.lang)
+            .attr(Name::prefixed("lang", NS_XML_PREFIX), &cx.lang);
+    }
+    writer.write(html)
+}
+
+/// Writes a table of contents for `tree` as nested `<ol>` lists, with at most `depth` levels (or every level if `depth` is `None`).
+///
+/// Chapters link to `href(chapter)`. Part headings are not links, unless the levels under them are cut off, in which case they link to the first chapter under them
+pub fn write_nav_list<W: std::io::Write>(
+    tree: &NavTree,
+    depth: Option<usize>,
+    href: &dyn Fn(&BookChapter) -> String,
+    w: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    let descend = depth.is_none_or(|depth| depth > 1);
+    w.write(XmlEvent::start_element("ol"))?;
+
+    for node in tree {
+        match &node.heading {
+            NavHeading::Chapter(title, chapter) => {
+                w.write(XmlEvent::start_element("li"))?;
+
+                w.write(XmlEvent::start_element("a").attr("href", &href(chapter)))?;
+                w.write(XmlEvent::characters(title))?;
+                w.write(XmlEvent::end_element())?;
             }
-            crate::bookir::BreakType::SoftLine | crate::bookir::BreakType::HardLine => {
-                writer.write(XmlEvent::start_element("br"))?;
-                writer.write(XmlEvent::end_element())
+            NavHeading::Heading(head) if !descend => {
+                // Without the nested list, a heading must be a link, so it links to the first chapter under it
+                let first_chapter =
+                    node.children
+                        .iter()
+                        .flat_map(NavTree::nested)
+                        .find_map(|node| match &node.heading {
+                            NavHeading::Chapter(_, chapter) => Some(chapter),
+                            _ => None,
+                        });
+                let Some(chapter) = first_chapter else {
+                    continue;
+                };
+                w.write(XmlEvent::start_element("li"))?;
+                w.write(XmlEvent::start_element("a").attr("href", &href(chapter)))?;
+                w.write(XmlEvent::characters(head))?;
+                w.write(XmlEvent::end_element())?;
             }
-        },
-        RichText::List(list) => {
-            match list.list_style {
-                ListStyle::Ordered(n) => {
-                    writer.write(XmlEvent::start_element("ol").attr("start", &format!("{n}")))?
-                }
-                ListStyle::Unordered => writer.write(XmlEvent::start_element("ul"))?,
+            NavHeading::Heading(head) => {
+                w.write(XmlEvent::start_element("li"))?;
+                w.write(XmlEvent::start_element("span"))?;
+                w.write(XmlEvent::characters(head))?;
+                w.write(XmlEvent::end_element())?;
             }
-
-            for item in &list.elems {
-                writer.write(XmlEvent::start_element("li"))?;
-                for elem in &item.0 {
-                    write_rich_node(elem, writer)?;
-                }
-                writer.write(XmlEvent::end_element())?;
+            NavHeading::UnboundChapter(title) => {
+                w.write(XmlEvent::start_element("li"))?;
+                w.write(XmlEvent::start_element("span"))?;
+                w.write(XmlEvent::characters(title))?;
+                w.write(XmlEvent::end_element())?;
             }
-            writer.write(XmlEvent::end_element())
         }
-        RichText::BlockQuote(vec) => {
-            writer.write(XmlEvent::start_element("bq"))?;
-            for elem in vec {
-                write_rich_node(elem, writer)?;
-            }
-            writer.write(XmlEvent::end_element())
+        if let Some(children) = node.children.as_ref().filter(|_| descend) {
+            write_nav_list(children, depth.map(|depth| depth - 1), href, w)?;
         }
-        #[cfg(feature = "math")]
-        RichText::MathBlock(_) => todo!(),
-        #[cfg(feature = "math")]
-        RichText::InlineMath(_) => todo!(),
+        w.write(XmlEvent::end_element())?;
+    }
+    w.write(XmlEvent::end_element())
+}
+
+/// Writes the `<head>` of a document, linking to the stylesheets of `cx`
+pub fn write_head<W: std::io::Write>(
+    title: Option<&str>,
+    cx: &DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    writer.write(XmlEvent::start_element("head"))?;
+    if let Some(title) = title {
+        writer.write(XmlEvent::start_element("title"))?;
+        writer.write(XmlEvent::characters(title))?;
+        writer.write(XmlEvent::end_element())?; // </title>
+    }
+    for stylesheet in &cx.stylesheets {
+        let href = format!("{}{stylesheet}", cx.root_href);
+        writer.write(
+            XmlEvent::start_element("link")
+                .attr("rel", "stylesheet")
+                .attr("type", "text/css")
+                .attr("href", &href),
+        )?;
+        writer.write(XmlEvent::end_element())?; // </link>
     }
+    writer.write(XmlEvent::end_element()) // </head>
 }
 
+/// Writes the section holding the footnotes of a chapter, if it has any
+pub fn write_footnotes<W: std::io::Write>(
+    cx: &mut DocumentContext,
+    writer: &mut EventWriter<W>,
+) -> xml::writer::Result<()> {
+    if cx.notes.is_empty() {
+        return Ok(());
+    }
+
+    writer.write(cx.semantics(
+        XmlEvent::start_element("section"),
+        "footnotes",
+        "doc-endnotes",
+    ))?;
+    let notes = cx.notes.clone();
+    XhtmlRenderer { cx, writer }.render_footnotes(&notes)?;
+    writer.write(XmlEvent::end_element()) // </section>
+}
+
+/// Writes the document of `book`. `title` is the title of the chapter in the table of contents
 pub fn write_chapter<W: std::io::Write>(
     book: &BookChapter,
+    title: &str,
+    section: SectionType,
+    cx: &mut DocumentContext,
     writer: &mut EventWriter<W>,
 ) -> xml::writer::Result<()> {
-    writer.write(XmlEvent::StartDocument {
-        version: xml::common::XmlVersion::Version11,
-        encoding: Some("UTF-8"),
-        standalone: None,
-    })?;
-    writer.write(XmlEvent::start_element("html").ns(NS_NO_PREFIX, NS_XHTML_URI))?;
+    write_document_start(cx, writer)?;
+    write_head(Some(title), cx, writer)?;
 
     writer.write(XmlEvent::start_element("body"))?;
+    writer.write(
+        XmlEvent::start_element("section")
+            .attr(Name::prefixed("type", NS_EPUB_PREFIX), section.epub_type()),
+    )?;
     for elem in &book.content {
-        write_rich_node(elem, writer)?;
+        write_rich_node(elem, cx, writer)?;
+    }
+    if cx.footnotes == FootnotePlacement::EndOfChapter {
+        write_footnotes(cx, writer)?;
     }
-    writer.write(XmlEvent::end_element())?;
-    writer.write(XmlEvent::end_element())
+    writer.write(XmlEvent::end_element())?; // </section>
+    writer.write(XmlEvent::end_element())?; // </body>
+    writer.write(XmlEvent::end_element()) // </html>
 }
@@ -15,6 +15,11 @@ macro_rules! build_test_fn{
                     let test = test?;
                     println!(::core::concat!("(" $(,::core::stringify!($parts),)"-"+ "): {}"), test.file_name().display());
                     let path = test.path();
+                    // Books that sign their outputs fail to build without the signatures feature
+                    #[cfg(not(feature = "epub-signatures"))]
+                    if std::fs::read_to_string(path.join("book.toml"))?.contains("signing-key") {
+                        continue;
+                    }
                     Command::new("mdbook")
                         .arg("build")
                         .arg(path)
@@ -32,6 +37,315 @@ build_test_fn! {
     test output bookir;
     #[cfg(feature = "epub")]
     test output epub-fancy;
+    #[cfg(feature = "html")]
+    test output html-fancy;
+    #[cfg(feature = "pdf")]
+    test output pdf-fancy;
 
     test preprocessor add-copyright;
 }
+
+/// Books in `tests/invalid` must fail to build, reporting every error listed in their `errors.txt`
+#[test]
+fn run_invalid_tests() -> std::io::Result<()> {
+    for test in std::fs::read_dir("tests/invalid")? {
+        let test = test?;
+        println!("(invalid): {}", test.file_name().display());
+        let path = test.path();
+        let mut command = Command::new("mdbook");
+        command.arg("build").arg(&path).env(
+            "MDBOOK_output__bookir__command",
+            env!("CARGO_BIN_EXE_mdbook-bookir"),
+        );
+        // Books that test the checks of the epub and pdf backends can only fail as expected when they are built
+        #[cfg(feature = "epub")]
+        command.env(
+            "MDBOOK_output__epub_fancy__command",
+            env!("CARGO_BIN_EXE_mdbook-epub-fancy"),
+        );
+        #[cfg(not(feature = "epub"))]
+        if std::fs::read_to_string(path.join("book.toml"))?.contains("[output.epub-fancy") {
+            continue;
+        }
+        #[cfg(feature = "pdf")]
+        command.env(
+            "MDBOOK_output__pdf_fancy__command",
+            env!("CARGO_BIN_EXE_mdbook-pdf-fancy"),
+        );
+        #[cfg(not(feature = "pdf"))]
+        if std::fs::read_to_string(path.join("book.toml"))?.contains("[output.pdf-fancy") {
+            continue;
+        }
+        let output = command.output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if output.status.success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} built successfully", path.display()),
+            ));
+        }
+        for expected in std::fs::read_to_string(path.join("errors.txt"))?.lines() {
+            if !stderr.contains(expected) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("missing error `{expected}`, got:\n{stderr}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the book at `book` with the epub backend into the directory `name` of the test scratch directory, which is returned
+#[cfg(feature = "epub")]
+fn build_epub(book: &str, name: &str) -> std::io::Result<std::path::PathBuf> {
+    let dest = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if dest.exists() {
+        std::fs::remove_dir_all(&dest)?;
+    }
+    Command::new("mdbook")
+        .arg("build")
+        .arg(book)
+        .arg("-d")
+        .arg(&dest)
+        .env(
+            "MDBOOK_output__epub_fancy__command",
+            env!("CARGO_BIN_EXE_mdbook-epub-fancy"),
+        )
+        .status()?
+        .exit_ok()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    Ok(dest)
+}
+
+/// Building a reproducible book twice gives byte-identical epub files
+#[cfg(feature = "epub")]
+#[test]
+fn reproducible_builds_are_identical() -> std::io::Result<()> {
+    let first = build_epub("tests/mdbook-epub-fancy/reproducible", "reproducible-1")?;
+    // Zip timestamps have a resolution of two seconds, so the builds must be further apart for a timestamp taken from the clock to differ
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let second = build_epub("tests/mdbook-epub-fancy/reproducible", "reproducible-2")?;
+
+    let mut names = std::fs::read_dir(&first)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+    assert!(!names.is_empty(), "the book has no outputs");
+    for name in names {
+        let path = std::path::Path::new(&name);
+        if path.extension().is_some_and(|ext| ext == "epub") {
+            assert!(
+                std::fs::read(first.join(path))? == std::fs::read(second.join(path))?,
+                "{} differs between builds",
+                name.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file `name` in the container of the epub at `path`
+#[cfg(feature = "epub")]
+fn read_epub_file(path: &std::path::Path, name: &str) -> std::io::Result<String> {
+    use std::io::Read;
+
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut content = String::new();
+    zip.by_name(name)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Finds the manifest item of the NCX and the `toc` attribute of the spine in the package document `opf`
+#[cfg(feature = "epub")]
+fn ncx_references(opf: &str) -> std::io::Result<(Option<String>, Option<String>)> {
+    use xml::reader::{EventReader, XmlEvent};
+
+    let mut item_id = None;
+    let mut spine_toc = None;
+    for event in EventReader::from_str(opf) {
+        let event = event.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event
+        {
+            let attr = |key: &str| {
+                attributes
+                    .iter()
+                    .find(|attr| attr.name.local_name == key)
+                    .map(|attr| attr.value.clone())
+            };
+            match &*name.local_name {
+                "item" if attr("href").as_deref() == Some("toc.ncx") => {
+                    assert_eq!(
+                        attr("media-type").as_deref(),
+                        Some("application/x-dtbncx+xml")
+                    );
+                    item_id = attr("id");
+                }
+                "spine" => spine_toc = attr("toc"),
+                _ => {}
+            }
+        }
+    }
+    Ok((item_id, spine_toc))
+}
+
+/// With `ncx` set, the NCX is packaged, listed in the manifest, and referred to by the `toc` attribute of the spine
+#[cfg(feature = "epub")]
+#[test]
+fn ncx_is_referenced_by_the_spine() -> std::io::Result<()> {
+    let dest = build_epub("tests/mdbook-epub-fancy/ncx", "ncx")?;
+    let epub = dest.join("ncx.epub");
+
+    let ncx = read_epub_file(&epub, "toc.ncx")?;
+    assert!(
+        ncx.contains(
+            r#"<meta name="dtb:uid" content="urn:uuid:5b1f3a52-7c84-4d3e-9a0e-2f6d1c9b8e47" />"#
+        ),
+        "the NCX does not have the identifier of the book:\n{ncx}"
+    );
+    assert!(
+        ncx.contains(r#"<content src="chapter_1_1_1.xhtml" />"#),
+        "the NCX does not list the nested chapters:\n{ncx}"
+    );
+
+    let (item_id, spine_toc) = ncx_references(&read_epub_file(&epub, "ncx.opf")?)?;
+    assert!(item_id.is_some(), "the NCX is not in the manifest");
+    assert_eq!(spine_toc, item_id);
+    Ok(())
+}
+
+/// Copies the epub at `path`, appending a paragraph to the body of the file `name`, and returns the tampered container
+#[cfg(feature = "epub-signatures")]
+fn tamper_epub(path: &std::path::Path, name: &str) -> std::io::Result<Vec<u8>> {
+    use std::io::{Read, Write};
+
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut tampered = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for idx in 0..zip.len() {
+        let mut file = zip.by_index(idx)?;
+        if file.name() == name {
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            tampered.start_file(name, zip::write::FileOptions::default())?;
+            tampered.write_all(
+                content
+                    .replace("</body>", "<p>Tampered</p></body>")
+                    .as_bytes(),
+            )?;
+        } else {
+            // Copying the other files as they are keeps the uncompressed `mimetype` first in the container
+            tampered.raw_copy_file(file)?;
+        }
+    }
+    Ok(tampered.finish()?.into_inner())
+}
+
+/// A signed epub verifies against its public key, but not once one of the signed files is modified
+#[cfg(feature = "epub-signatures")]
+#[test]
+fn verify_epub_rejects_a_tampered_file() -> std::io::Result<()> {
+    use mdbook_fiction_tools::epub::{
+        config::SignaturePublicKey,
+        signature::{verify_epub, VerifyingKey},
+    };
+
+    let book = std::path::Path::new("tests/mdbook-epub-fancy/signatures");
+    let dest = build_epub("tests/mdbook-epub-fancy/signatures", "signatures")?;
+    let epub = dest.join("signatures.epub");
+
+    let public: SignaturePublicKey = toml::from_str(r#"public = "keys/public.pem""#)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let key = VerifyingKey::load(&public, book)?;
+
+    verify_epub(std::fs::File::open(&epub)?, &key)?;
+
+    let tampered = tamper_epub(&epub, "chapter_1.xhtml")?;
+    let err = verify_epub(std::io::Cursor::new(tampered), &key)
+        .expect_err("the tampered epub was verified");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{err}");
+    assert_eq!(
+        err.to_string(),
+        "`chapter_1.xhtml` has been modified since it was signed"
+    );
+    Ok(())
+}
+
+/// A container whose `mimetype` is compressed and wrong, and which has no `META-INF/container.xml`, is reported with an error for each problem
+#[cfg(feature = "epub")]
+#[test]
+fn check_epub_reports_a_bad_container() -> std::io::Result<()> {
+    use mdbook_fiction_tools::epub::check::{check_epub, Problem, Severity};
+    use std::io::Write;
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    zip.start_file(
+        "mimetype",
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated),
+    )?;
+    zip.write_all(b"application/zip")?;
+    zip.start_file("content.opf", zip::write::FileOptions::default())?;
+    zip.write_all(b"<package />")?;
+    let container = zip.finish()?;
+
+    let report = check_epub(std::io::Cursor::new(container.into_inner()))?;
+    assert!(report.has_errors(), "{report}");
+    let error = |file: Option<&str>, message: &str| Problem {
+        severity: Severity::Error,
+        file: file.map(str::to_string),
+        message: message.to_string(),
+    };
+    assert_eq!(
+        report.0,
+        [
+            error(Some("mimetype"), "must be stored without compression"),
+            error(
+                Some("mimetype"),
+                "must contain exactly `application/epub+zip`"
+            ),
+            error(None, "`META-INF/container.xml` is missing"),
+        ],
+        "{report}"
+    );
+    Ok(())
+}
+
+/// Footnote references link to their notes, which are numbered in order of first reference in each chapter and link back to it
+#[cfg(feature = "epub")]
+#[test]
+fn footnotes_are_linked_and_numbered() -> std::io::Result<()> {
+    let dest = build_epub("tests/common/footnotes", "footnotes")?;
+    // The book also has the markdown output, so each output is in its own directory
+    let epub = dest.join("epub-fancy").join("footnotes.epub");
+
+    let chapter_1 = read_epub_file(&epub, "chapter_1.xhtml")?;
+    for markup in [
+        r##"dawn<a epub:type="noteref" href="#fn-dawn" id="fnref-dawn"><sup>1</sup></a>"##,
+        r##"hour<a epub:type="noteref" href="#fn-kay" id="fnref-kay"><sup>2</sup></a>"##,
+        // Only the first reference to a note is the target of its back link
+        r##"noon<a epub:type="noteref" href="#fn-kay"><sup>2</sup></a>"##,
+        r##"referenced<a epub:type="noteref" href="#fn-quote" id="fnref-quote"><sup>3</sup></a>"##,
+        concat!(
+            r##"<section epub:type="footnotes">"##,
+            r##"<aside epub:type="footnote" id="fn-dawn"><a href="#fnref-dawn">1.</a> <p>Or what passed for dawn"##
+        ),
+        r##"<p>This note has a second paragraph.</p></aside><aside epub:type="footnote" id="fn-kay"><a href="#fnref-kay">2.</a> "##,
+        r##"<aside epub:type="footnote" id="fn-quote"><a href="#fnref-quote">3.</a> <p>Quoted from the <i>Chronicles</i>.</p></aside></section>"##,
+    ] {
+        assert!(
+            chapter_1.contains(markup),
+            "chapter_1.xhtml does not contain `{markup}`:\n{chapter_1}"
+        );
+    }
+
+    let chapter_2 = read_epub_file(&epub, "chapter_2.xhtml")?;
+    assert!(
+        chapter_2.contains(
+            r##"<a epub:type="noteref" href="#fn-again" id="fnref-again"><sup>1</sup></a>"##
+        ),
+        "the numbering of notes does not start again in chapter_2.xhtml:\n{chapter_2}"
+    );
+    Ok(())
+}